@@ -0,0 +1,82 @@
+use super::{linear_to_srgb, srgb_to_linear};
+use image::RgbImage;
+use std::f32::consts::PI;
+
+/// Digits of the base 83 encoding used by BlurHash
+const BASE83: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Encode an image as a BlurHash string
+/// - image: Image to encode, best downsampled first as every pixel is visited per component
+/// - x_components: Number of horizontal components (1-9)
+/// - y_components: Number of vertical components (1-9)
+///
+/// Follows the reference implementation at https://github.com/woltapp/blurhash.
+pub(crate) fn encode(image: &RgbImage, x_components: u32, y_components: u32) -> String {
+    let (width, height) = image.dimensions();
+    let linear = image
+        .pixels()
+        .map(|pixel| pixel.0.map(|value| srgb_to_linear(value as f32 / 255.0)))
+        .collect::<Vec<_>>();
+
+    // Cosine transform, the DC component first, then row by row
+    let mut factors = Vec::with_capacity((x_components * y_components) as usize);
+    for j in 0..y_components {
+        for i in 0..x_components {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0.0f32; 3];
+            for y in 0..height {
+                let basis_y = (PI * j as f32 * y as f32 / height as f32).cos();
+                for x in 0..width {
+                    let basis = basis_y * (PI * i as f32 * x as f32 / width as f32).cos();
+                    let pixel = linear[(y * width + x) as usize];
+                    for channel in 0..3 {
+                        factor[channel] += basis * pixel[channel];
+                    }
+                }
+            }
+            let scale = normalisation / (width * height).max(1) as f32;
+            factors.push(factor.map(|value| value * scale));
+        }
+    }
+    let (dc, ac) = factors.split_first().expect("At least one component");
+
+    let mut hash = String::new();
+    push_base83(&mut hash, (x_components - 1) + (y_components - 1) * 9, 1);
+
+    let maximum = if ac.is_empty() {
+        push_base83(&mut hash, 0, 1);
+        1.0
+    } else {
+        let actual_maximum = ac
+            .iter()
+            .flatten()
+            .fold(0.0f32, |maximum, value| maximum.max(value.abs()));
+        let quantised_maximum = (actual_maximum * 166.0 - 0.5).floor().clamp(0.0, 82.0) as u32;
+        push_base83(&mut hash, quantised_maximum, 1);
+        (quantised_maximum + 1) as f32 / 166.0
+    };
+
+    let [r, g, b] = dc.map(|value| (linear_to_srgb(value) * 255.0 + 0.5) as u32);
+    push_base83(&mut hash, (r << 16) + (g << 8) + b, 4);
+
+    for factor in ac {
+        let [r, g, b] = factor.map(|value| {
+            let value = value / maximum;
+            (value.signum() * value.abs().sqrt() * 9.0 + 9.5)
+                .floor()
+                .clamp(0.0, 18.0) as u32
+        });
+        push_base83(&mut hash, r * 19 * 19 + g * 19 + b, 2);
+    }
+
+    hash
+}
+
+/// Append a value as `length` base 83 digits, most significant first
+fn push_base83(hash: &mut String, value: u32, length: u32) {
+    for digit in (0..length).rev() {
+        let index = value / 83u32.pow(digit) % 83;
+        hash.push(BASE83[index as usize] as char);
+    }
+}
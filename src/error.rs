@@ -0,0 +1,152 @@
+use image::ImageError;
+use wasm_bindgen::JsValue;
+
+/// Kind of failure, thrown to JS as the `code` of the error
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ErrorCode {
+    /// The input isn't an image, or the requested format isn't supported
+    UnsupportedFormat,
+    /// The input image is corrupt or truncated
+    DecodeFailed,
+    /// The input image has more pixels than allowed
+    ImageTooLarge,
+    /// The output couldn't be encoded, like when the image is too large for the format
+    EncodeFailed,
+    /// An argument or option is out of its valid range
+    InvalidArgument,
+    /// Quantization couldn't reach `min_quality`
+    QualityTooLow,
+    /// The progress callback threw
+    CallbackFailed,
+}
+
+impl ErrorCode {
+    /// Code as seen in JS
+    fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::UnsupportedFormat => "UNSUPPORTED_FORMAT",
+            ErrorCode::DecodeFailed => "DECODE_FAILED",
+            ErrorCode::ImageTooLarge => "IMAGE_TOO_LARGE",
+            ErrorCode::EncodeFailed => "ENCODE_FAILED",
+            ErrorCode::InvalidArgument => "INVALID_ARGUMENT",
+            ErrorCode::QualityTooLow => "QUALITY_TOO_LOW",
+            ErrorCode::CallbackFailed => "CALLBACK_FAILED",
+        }
+    }
+}
+
+/// Error thrown to JS as an `Error` with a `code` property, so callers can tell failures apart
+#[derive(Clone, Debug)]
+pub struct Error {
+    code: ErrorCode,
+    message: String,
+}
+
+impl Error {
+    pub(crate) fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Error {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Kind of failure
+    pub(crate) fn kind(&self) -> ErrorCode {
+        self.code
+    }
+
+    /// Code as seen in JS, like `UNSUPPORTED_FORMAT`
+    pub(crate) fn code(&self) -> &'static str {
+        self.code.as_str()
+    }
+
+    pub(crate) fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<Error> for JsValue {
+    fn from(error: Error) -> Self {
+        let js_error = js_sys::Error::new(&error.message);
+        js_error.set_name(error.code.as_str());
+        js_sys::Reflect::set(
+            &js_error,
+            &JsValue::from_str("code"),
+            &JsValue::from_str(error.code.as_str()),
+        )
+        .expect("Failed to set the error code");
+        js_error.into()
+    }
+}
+
+impl From<ImageError> for Error {
+    fn from(error: ImageError) -> Self {
+        let code = match &error {
+            ImageError::Unsupported(_) => ErrorCode::UnsupportedFormat,
+            ImageError::Decoding(_) => ErrorCode::DecodeFailed,
+            ImageError::Limits(_) => ErrorCode::ImageTooLarge,
+            ImageError::Parameter(_) => ErrorCode::InvalidArgument,
+            ImageError::Encoding(_) | ImageError::IoError(_) => ErrorCode::EncodeFailed,
+        };
+        Error::new(code, error.to_string())
+    }
+}
+
+impl From<imagequant::Error> for Error {
+    fn from(error: imagequant::Error) -> Self {
+        let code = match error {
+            imagequant::Error::QualityTooLow => ErrorCode::QualityTooLow,
+            imagequant::Error::ValueOutOfRange => ErrorCode::InvalidArgument,
+            _ => ErrorCode::EncodeFailed,
+        };
+        Error::new(code, error.to_string())
+    }
+}
+
+impl From<png::DecodingError> for Error {
+    fn from(error: png::DecodingError) -> Self {
+        Error::new(ErrorCode::DecodeFailed, error.to_string())
+    }
+}
+
+impl From<image_webp::DecodingError> for Error {
+    fn from(error: image_webp::DecodingError) -> Self {
+        Error::new(ErrorCode::DecodeFailed, error.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::new(ErrorCode::EncodeFailed, error.to_string())
+    }
+}
+
+impl From<png::EncodingError> for Error {
+    fn from(error: png::EncodingError) -> Self {
+        Error::new(ErrorCode::EncodeFailed, error.to_string())
+    }
+}
+
+impl From<gif::EncodingError> for Error {
+    fn from(error: gif::EncodingError) -> Self {
+        Error::new(ErrorCode::EncodeFailed, error.to_string())
+    }
+}
+
+impl From<tiff::TiffError> for Error {
+    fn from(error: tiff::TiffError) -> Self {
+        Error::new(ErrorCode::EncodeFailed, error.to_string())
+    }
+}
+
+impl From<image_webp::EncodingError> for Error {
+    fn from(error: image_webp::EncodingError) -> Self {
+        Error::new(ErrorCode::EncodeFailed, error.to_string())
+    }
+}
+
+impl From<jpeg_encoder::EncodingError> for Error {
+    fn from(error: jpeg_encoder::EncodingError) -> Self {
+        Error::new(ErrorCode::EncodeFailed, error.to_string())
+    }
+}
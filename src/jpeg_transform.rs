@@ -0,0 +1,1051 @@
+use super::Transform;
+use super::error::{Error, ErrorCode};
+use super::metadata::reset_exif_orientation;
+
+/// Start of the EXIF data in an APP1 segment, after the marker and length
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// Natural (row major) index of every coefficient in zigzag order
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Coefficients of an 8x8 block in natural order
+type Block = [i16; 64];
+
+/// Transform a JPEG image without decoding its pixels, like jpegtran, by moving the DCT
+/// coefficients of every block
+/// - bytes: JPEG image data
+/// - transform: Rotation or flip to apply
+/// - max_pixels: Largest width times height to transform, 0 for no limit
+///
+/// Only sequential Huffman coded JPEG is supported. A flip moves the blocks along an axis, so the
+/// image size along that axis must be a multiple of the block size (the MCU, 8 or 16 pixels
+/// depending on the chroma subsampling), as a partial block can't move to the other edge. Those
+/// cases throw `UNSUPPORTED_FORMAT`. Metadata segments are copied with the EXIF orientation reset,
+/// restart markers are left out and the Huffman tables are rebuilt for the new coefficient order.
+pub(crate) fn transform(
+    bytes: &[u8],
+    transform: Transform,
+    max_pixels: u32,
+) -> Result<Vec<u8>, Error> {
+    let (transpose, flip_h, flip_v) = match transform {
+        Transform::None => return Ok(bytes.to_vec()),
+        // Rotations transpose first, then flip the transposed image
+        Transform::Rotate90 => (true, true, false),
+        Transform::Rotate180 => (false, true, true),
+        Transform::Rotate270 => (true, false, true),
+        Transform::FlipH => (false, true, false),
+        Transform::FlipV => (false, false, true),
+    };
+
+    let mut jpeg = parse(bytes, max_pixels)?;
+    let frame = jpeg
+        .frame
+        .as_mut()
+        .ok_or_else(|| corrupt("No frame header"))?;
+    // The flips apply after the transpose, so they flip the sides the other way around
+    let (mcu_width, mcu_height) = frame.mcu_size();
+    let (flips_width, flips_height) = if transpose {
+        (flip_v, flip_h)
+    } else {
+        (flip_h, flip_v)
+    };
+    if (flips_width && frame.width % mcu_width != 0)
+        || (flips_height && frame.height % mcu_height != 0)
+    {
+        return Err(Error::new(
+            ErrorCode::UnsupportedFormat,
+            format!(
+                "A {}x{} pixel JPEG can't be transformed losslessly, as the sides it flips must \
+                 be multiples of its {mcu_width}x{mcu_height} pixel blocks",
+                frame.width, frame.height
+            ),
+        ));
+    }
+    if transpose {
+        frame.transpose();
+        for table in jpeg.quant_tables.iter_mut().flatten() {
+            table.values = transpose_block(&table.values);
+        }
+    }
+    for component in &mut frame.components {
+        component.transform(transpose, flip_h, flip_v);
+    }
+    // The pixels no longer match the orientation they were stored with, so viewers must not
+    // apply it on top of the transform
+    for segment in &mut jpeg.segments {
+        if segment[1] == 0xe1 && segment[4..].starts_with(EXIF_HEADER) {
+            reset_exif_orientation(&mut segment[4 + EXIF_HEADER.len()..]);
+        }
+    }
+
+    Ok(write(&jpeg))
+}
+
+/// Rewrite a sequential JPEG image with Huffman tables fitted to its coefficients, which makes it
+/// smaller without touching the pixels
+/// - bytes: JPEG image data
+///
+/// The scans keep their components together, unlike the fitted tables of jpeg-encoder, which give
+/// baseline JPEG one scan per component.
+pub(crate) fn optimize(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    Ok(write(&parse(bytes, 0)?))
+}
+
+/// Error for data that doesn't follow the JPEG format
+fn corrupt(message: &str) -> Error {
+    Error::new(ErrorCode::DecodeFailed, format!("Invalid JPEG: {message}"))
+}
+
+/// Error for valid JPEG that can't be transformed
+fn unsupported(message: &str) -> Error {
+    Error::new(ErrorCode::UnsupportedFormat, message)
+}
+
+/// Quantization table, with the entries in natural order
+#[derive(Clone)]
+struct QuantTable {
+    /// Whether the entries are 16 bits, otherwise 8 bits
+    wide: bool,
+    values: [u16; 64],
+}
+
+/// Component of the frame with its coefficients
+struct Component {
+    id: u8,
+    /// Horizontal sampling factor
+    h: u8,
+    /// Vertical sampling factor
+    v: u8,
+    /// Quantization table id
+    quant_table: u8,
+    /// Coefficient blocks of the whole frame, padded to full MCUs, row by row
+    blocks: Vec<Block>,
+    /// Number of blocks per row, including the padding
+    blocks_wide: usize,
+    /// Number of block rows, including the padding
+    blocks_high: usize,
+}
+
+impl Component {
+    /// Move the blocks and the coefficients inside of them
+    fn transform(&mut self, transpose: bool, flip_h: bool, flip_v: bool) {
+        let (width, height) = if transpose {
+            (self.blocks_high, self.blocks_wide)
+        } else {
+            (self.blocks_wide, self.blocks_high)
+        };
+        let mut blocks = Vec::with_capacity(self.blocks.len());
+        for y in 0..height {
+            for x in 0..width {
+                let x0 = if flip_h { width - 1 - x } else { x };
+                let y0 = if flip_v { height - 1 - y } else { y };
+                let (source_x, source_y) = if transpose { (y0, x0) } else { (x0, y0) };
+                let source = &self.blocks[source_y * self.blocks_wide + source_x];
+                blocks.push(transform_block(source, transpose, flip_h, flip_v));
+            }
+        }
+        self.blocks = blocks;
+        self.blocks_wide = width;
+        self.blocks_high = height;
+    }
+}
+
+/// Frame header with the coefficients of all components
+struct Frame {
+    /// SOF marker, baseline or extended sequential
+    marker: u8,
+    /// Bits per sample, 8 or 12
+    precision: u8,
+    width: u32,
+    height: u32,
+    components: Vec<Component>,
+}
+
+impl Frame {
+    /// Largest horizontal and vertical sampling factors
+    fn max_sampling(&self) -> (u32, u32) {
+        self.components.iter().fold((1, 1), |(h, v), component| {
+            (h.max(component.h as u32), v.max(component.v as u32))
+        })
+    }
+
+    /// Width and height of an MCU in pixels
+    fn mcu_size(&self) -> (u32, u32) {
+        let (h, v) = self.max_sampling();
+        (8 * h, 8 * v)
+    }
+
+    /// Width and height of a component in blocks, without the padding to full MCUs
+    fn component_blocks(&self, component: &Component) -> (usize, usize) {
+        let (h, v) = self.max_sampling();
+        let width = (self.width * component.h as u32).div_ceil(h);
+        let height = (self.height * component.v as u32).div_ceil(v);
+        (width.div_ceil(8) as usize, height.div_ceil(8) as usize)
+    }
+
+    /// Swap the width and height, and the sampling factors of every component
+    fn transpose(&mut self) {
+        (self.width, self.height) = (self.height, self.width);
+        for component in &mut self.components {
+            (component.h, component.v) = (component.v, component.h);
+        }
+    }
+}
+
+/// Scan header, the components coded in the scan and their Huffman tables
+struct Scan {
+    /// Index into the frame components, with the DC and AC table ids
+    components: Vec<(usize, u8, u8)>,
+}
+
+/// Parsed JPEG image
+struct Jpeg {
+    /// APPn and COM segments, marker included, in their original order
+    segments: Vec<Vec<u8>>,
+    quant_tables: [Option<QuantTable>; 4],
+    frame: Option<Frame>,
+    scans: Vec<Scan>,
+}
+
+/// Huffman table for decoding, as the code lengths and symbols of a DHT segment
+#[derive(Clone)]
+struct DecodeTable {
+    /// Largest code of every length, -1 when there are none
+    max_code: [i32; 17],
+    /// Index of the first symbol of every length minus the first code of that length
+    offset: [i32; 17],
+    symbols: Vec<u8>,
+}
+
+impl DecodeTable {
+    /// Build the canonical codes of the code lengths, like Annex C of the JPEG specification
+    fn new(counts: &[u8; 16], symbols: Vec<u8>) -> Self {
+        let mut max_code = [-1; 17];
+        let mut offset = [0; 17];
+        let (mut code, mut index) = (0i32, 0i32);
+        for length in 1..=16 {
+            let count = counts[length - 1] as i32;
+            offset[length] = index - code;
+            if count > 0 {
+                code += count;
+                index += count;
+                max_code[length] = code - 1;
+            }
+            code <<= 1;
+        }
+        DecodeTable {
+            max_code,
+            offset,
+            symbols,
+        }
+    }
+
+    /// Read the next symbol
+    fn decode(&self, reader: &mut BitReader) -> Result<u8, Error> {
+        let mut code = 0i32;
+        for length in 1..=16 {
+            code = code << 1 | reader.bit()? as i32;
+            if code <= self.max_code[length] {
+                return self
+                    .symbols
+                    .get((code + self.offset[length]) as usize)
+                    .copied()
+                    .ok_or_else(|| corrupt("Huffman code without a symbol"));
+            }
+        }
+        Err(corrupt("Unknown Huffman code"))
+    }
+}
+
+/// Parse the segments and decode the coefficients of a JPEG image
+fn parse(bytes: &[u8], max_pixels: u32) -> Result<Jpeg, Error> {
+    if !bytes.starts_with(&[0xff, 0xd8]) {
+        return Err(corrupt("Missing start of image"));
+    }
+    let mut jpeg = Jpeg {
+        segments: Vec::new(),
+        quant_tables: Default::default(),
+        frame: None,
+        scans: Vec::new(),
+    };
+    let mut dc_tables: [Option<DecodeTable>; 4] = Default::default();
+    let mut ac_tables: [Option<DecodeTable>; 4] = Default::default();
+    let mut restart_interval = 0;
+
+    let mut position = 2;
+    loop {
+        // Markers may be preceded by any number of fill bytes
+        while bytes.get(position) == Some(&0xff) && bytes.get(position + 1) == Some(&0xff) {
+            position += 1;
+        }
+        let marker = match bytes.get(position..position + 2) {
+            Some(&[0xff, marker]) => marker,
+            _ => return Err(corrupt("Expected a marker")),
+        };
+        if marker == 0xd9 {
+            break;
+        }
+        let length = bytes
+            .get(position + 2..position + 4)
+            .map(|length| u16::from_be_bytes([length[0], length[1]]) as usize)
+            .filter(|&length| length >= 2)
+            .ok_or_else(|| corrupt("Truncated segment"))?;
+        let segment = bytes
+            .get(position + 4..position + 2 + length)
+            .ok_or_else(|| corrupt("Truncated segment"))?;
+        let segment_end = position + 2 + length;
+        match marker {
+            0xe0..=0xef | 0xfe => jpeg.segments.push(bytes[position..segment_end].to_vec()),
+            0xdb => parse_quant_tables(segment, &mut jpeg.quant_tables)?,
+            0xc4 => parse_huffman_tables(segment, &mut dc_tables, &mut ac_tables)?,
+            0xdd => {
+                let interval = segment.get(..2).ok_or_else(|| corrupt("Truncated DRI"))?;
+                restart_interval = u16::from_be_bytes([interval[0], interval[1]]) as usize;
+            }
+            0xc0 | 0xc1 => {
+                if jpeg.frame.is_some() {
+                    return Err(corrupt("More than one frame"));
+                }
+                jpeg.frame = Some(parse_frame(marker, segment, max_pixels)?);
+            }
+            0xc2 => {
+                return Err(unsupported(
+                    "Progressive JPEG can't be transformed losslessly",
+                ));
+            }
+            0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf => {
+                return Err(unsupported(
+                    "Only sequential Huffman coded JPEG can be transformed losslessly",
+                ));
+            }
+            0xda => {
+                let frame = jpeg
+                    .frame
+                    .as_mut()
+                    .ok_or_else(|| corrupt("Scan before frame"))?;
+                let (scan, tables) = parse_scan(segment, frame, &dc_tables, &ac_tables)?;
+                let (data, end) = entropy_data(bytes, segment_end);
+                let mut reader = BitReader::new(data);
+                decode_scan(&mut reader, frame, &scan, &tables, restart_interval)?;
+                jpeg.scans.push(scan);
+                position = end;
+                continue;
+            }
+            _ => {}
+        }
+        position = segment_end;
+    }
+
+    let frame = jpeg
+        .frame
+        .as_ref()
+        .ok_or_else(|| corrupt("No frame header"))?;
+    for (index, component) in frame.components.iter().enumerate() {
+        // Tables only need to come before the scans, not before the frame
+        if jpeg.quant_tables[component.quant_table as usize].is_none() {
+            return Err(corrupt("Missing quantization table"));
+        }
+        if !jpeg.scans.iter().any(|scan| {
+            scan.components
+                .iter()
+                .any(|&(scan_index, ..)| scan_index == index)
+        }) {
+            return Err(corrupt("Component without a scan"));
+        }
+    }
+    Ok(jpeg)
+}
+
+/// Read the quantization tables of a DQT segment
+fn parse_quant_tables(segment: &[u8], tables: &mut [Option<QuantTable>; 4]) -> Result<(), Error> {
+    let mut offset = 0;
+    while let Some(&info) = segment.get(offset) {
+        let wide = info >> 4 != 0;
+        let entry_size = if wide { 2 } else { 1 };
+        let entries = segment
+            .get(offset + 1..offset + 1 + 64 * entry_size)
+            .ok_or_else(|| corrupt("Truncated DQT"))?;
+        let mut values = [0; 64];
+        for (index, entry) in entries.chunks_exact(entry_size).enumerate() {
+            values[ZIGZAG[index]] = entry.iter().fold(0, |sum, &byte| sum << 8 | byte as u16);
+        }
+        *tables
+            .get_mut((info & 0x0f) as usize)
+            .ok_or_else(|| corrupt("Quantization table id above 3"))? =
+            Some(QuantTable { wide, values });
+        offset += 1 + 64 * entry_size;
+    }
+    Ok(())
+}
+
+/// Read the Huffman tables of a DHT segment
+fn parse_huffman_tables(
+    segment: &[u8],
+    dc_tables: &mut [Option<DecodeTable>; 4],
+    ac_tables: &mut [Option<DecodeTable>; 4],
+) -> Result<(), Error> {
+    let mut offset = 0;
+    while let Some(&info) = segment.get(offset) {
+        let counts: [u8; 16] = segment
+            .get(offset + 1..offset + 17)
+            .and_then(|counts| counts.try_into().ok())
+            .ok_or_else(|| corrupt("Truncated DHT"))?;
+        let count = counts.iter().map(|&count| count as usize).sum::<usize>();
+        let symbols = segment
+            .get(offset + 17..offset + 17 + count)
+            .ok_or_else(|| corrupt("Truncated DHT"))?;
+        let tables = if info >> 4 == 0 {
+            &mut *dc_tables
+        } else {
+            &mut *ac_tables
+        };
+        *tables
+            .get_mut((info & 0x0f) as usize)
+            .ok_or_else(|| corrupt("Huffman table id above 3"))? =
+            Some(DecodeTable::new(&counts, symbols.to_vec()));
+        offset += 17 + count;
+    }
+    Ok(())
+}
+
+/// Read a SOF segment and allocate the blocks of every component
+fn parse_frame(marker: u8, segment: &[u8], max_pixels: u32) -> Result<Frame, Error> {
+    let header = segment.get(..6).ok_or_else(|| corrupt("Truncated SOF"))?;
+    let precision = header[0];
+    let height = u16::from_be_bytes([header[1], header[2]]) as u32;
+    let width = u16::from_be_bytes([header[3], header[4]]) as u32;
+    let count = header[5] as usize;
+    if precision != 8 && precision != 12 {
+        return Err(corrupt("Sample precision must be 8 or 12 bits"));
+    }
+    if width == 0 || height == 0 {
+        return Err(unsupported(
+            "JPEG with the height in a DNL marker can't be transformed losslessly",
+        ));
+    }
+    if max_pixels > 0 && width as u64 * height as u64 > max_pixels as u64 {
+        return Err(Error::new(
+            ErrorCode::ImageTooLarge,
+            format!("Image of {width}x{height} pixels exceeds max_pixels {max_pixels}"),
+        ));
+    }
+    let specs = segment
+        .get(6..6 + 3 * count)
+        .filter(|_| count > 0)
+        .ok_or_else(|| corrupt("Truncated SOF"))?;
+
+    let mut components = Vec::with_capacity(count);
+    for spec in specs.chunks_exact(3) {
+        let (h, v) = (spec[1] >> 4, spec[1] & 0x0f);
+        if !(1..=4).contains(&h) || !(1..=4).contains(&v) {
+            return Err(corrupt("Sampling factor outside of 1-4"));
+        }
+        if spec[2] > 3 {
+            return Err(corrupt("Quantization table id above 3"));
+        }
+        components.push(Component {
+            id: spec[0],
+            h,
+            v,
+            quant_table: spec[2],
+            blocks: Vec::new(),
+            blocks_wide: 0,
+            blocks_high: 0,
+        });
+    }
+    // A single component is never interleaved, so its MCU is one block whatever its sampling
+    if let [component] = components.as_mut_slice() {
+        (component.h, component.v) = (1, 1);
+    }
+
+    let mut frame = Frame {
+        marker,
+        precision,
+        width,
+        height,
+        components,
+    };
+    let (mcu_width, mcu_height) = frame.mcu_size();
+    let (mcus_wide, mcus_high) = (
+        width.div_ceil(mcu_width) as usize,
+        height.div_ceil(mcu_height) as usize,
+    );
+    for component in &mut frame.components {
+        component.blocks_wide = mcus_wide * component.h as usize;
+        component.blocks_high = mcus_high * component.v as usize;
+        component.blocks = vec![[0; 64]; component.blocks_wide * component.blocks_high];
+    }
+    Ok(frame)
+}
+
+/// Read a SOS segment, returning it with the DC and AC tables of every component of the scan
+fn parse_scan(
+    segment: &[u8],
+    frame: &Frame,
+    dc_tables: &[Option<DecodeTable>; 4],
+    ac_tables: &[Option<DecodeTable>; 4],
+) -> Result<(Scan, Vec<(DecodeTable, DecodeTable)>), Error> {
+    let count = *segment.first().ok_or_else(|| corrupt("Truncated SOS"))? as usize;
+    let specs = segment
+        .get(1..1 + 2 * count)
+        .filter(|_| (1..=4).contains(&count))
+        .ok_or_else(|| corrupt("Truncated SOS"))?;
+    if segment.get(1 + 2 * count..4 + 2 * count) != Some(&[0, 63, 0]) {
+        return Err(corrupt("Sequential scan must cover all coefficients"));
+    }
+
+    let mut components = Vec::with_capacity(count);
+    let mut tables = Vec::with_capacity(count);
+    for spec in specs.chunks_exact(2) {
+        let index = frame
+            .components
+            .iter()
+            .position(|component| component.id == spec[0])
+            .ok_or_else(|| corrupt("Scan of an unknown component"))?;
+        let (dc_id, ac_id) = (spec[1] >> 4, spec[1] & 0x0f);
+        let table = |tables: &[Option<DecodeTable>; 4], id: u8| {
+            tables
+                .get(id as usize)
+                .cloned()
+                .flatten()
+                .ok_or_else(|| corrupt("Missing Huffman table"))
+        };
+        components.push((index, dc_id, ac_id));
+        tables.push((table(dc_tables, dc_id)?, table(ac_tables, ac_id)?));
+    }
+    Ok((Scan { components }, tables))
+}
+
+/// Find the end of the entropy coded data starting at `start`, the next marker other than a
+/// restart marker, returning the data and the position of that marker
+fn entropy_data(bytes: &[u8], start: usize) -> (&[u8], usize) {
+    let mut end = start;
+    while end + 1 < bytes.len() {
+        if bytes[end] == 0xff && bytes[end + 1] != 0 && !(0xd0..=0xd7).contains(&bytes[end + 1]) {
+            break;
+        }
+        end += 1;
+    }
+    let end = end.min(bytes.len());
+    (&bytes[start.min(end)..end], end)
+}
+
+/// Position of every block of a scan, in coding order, as the component of the scan and the
+/// block coordinates, with the number of blocks per MCU
+fn scan_blocks(frame: &Frame, components: &[usize]) -> (Vec<(usize, usize, usize)>, usize) {
+    let mut blocks = Vec::new();
+    if let [index] = *components {
+        // A single component is coded block by block, leaving out the padding
+        let (width, height) = frame.component_blocks(&frame.components[index]);
+        for y in 0..height {
+            for x in 0..width {
+                blocks.push((0, x, y));
+            }
+        }
+        return (blocks, 1);
+    }
+
+    let (mcu_width, mcu_height) = frame.mcu_size();
+    let (mcus_wide, mcus_high) = (
+        frame.width.div_ceil(mcu_width) as usize,
+        frame.height.div_ceil(mcu_height) as usize,
+    );
+    let blocks_per_mcu = components
+        .iter()
+        .map(|&index| {
+            let component = &frame.components[index];
+            component.h as usize * component.v as usize
+        })
+        .sum();
+    for mcu_y in 0..mcus_high {
+        for mcu_x in 0..mcus_wide {
+            for (scan_index, &index) in components.iter().enumerate() {
+                let component = &frame.components[index];
+                let (h, v) = (component.h as usize, component.v as usize);
+                for y in 0..v {
+                    for x in 0..h {
+                        blocks.push((scan_index, mcu_x * h + x, mcu_y * v + y));
+                    }
+                }
+            }
+        }
+    }
+    (blocks, blocks_per_mcu)
+}
+
+/// Decode the coefficients of a scan into the frame
+fn decode_scan(
+    reader: &mut BitReader,
+    frame: &mut Frame,
+    scan: &Scan,
+    tables: &[(DecodeTable, DecodeTable)],
+    restart_interval: usize,
+) -> Result<(), Error> {
+    let indexes = scan
+        .components
+        .iter()
+        .map(|&(index, ..)| index)
+        .collect::<Vec<_>>();
+    let (blocks, blocks_per_mcu) = scan_blocks(frame, &indexes);
+    let mut predictions = vec![0i32; indexes.len()];
+
+    for (number, &(scan_index, x, y)) in blocks.iter().enumerate() {
+        let mcu = number / blocks_per_mcu;
+        if restart_interval > 0
+            && mcu > 0
+            && mcu % restart_interval == 0
+            && number % blocks_per_mcu == 0
+        {
+            reader.restart()?;
+            predictions.fill(0);
+        }
+        let (dc_table, ac_table) = &tables[scan_index];
+        let component = &mut frame.components[scan.components[scan_index].0];
+        let block = &mut component.blocks[y * component.blocks_wide + x];
+
+        let size = dc_table.decode(reader)?;
+        if size > 16 {
+            return Err(corrupt("DC difference of more than 16 bits"));
+        }
+        predictions[scan_index] =
+            predictions[scan_index].wrapping_add(extend(reader.bits(size)?, size));
+        block[0] = predictions[scan_index] as i16;
+        let mut k = 1;
+        while k < 64 {
+            let symbol = ac_table.decode(reader)?;
+            let (run, size) = ((symbol >> 4) as usize, symbol & 0x0f);
+            if size == 0 {
+                if run != 15 {
+                    break;
+                }
+                k += 16;
+                continue;
+            }
+            k += run;
+            if k > 63 {
+                return Err(corrupt("Coefficient index past 63"));
+            }
+            block[ZIGZAG[k]] = extend(reader.bits(size)?, size) as i16;
+            k += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Sign extend the additional bits of a coefficient of `size` bits
+fn extend(bits: u32, size: u8) -> i32 {
+    if size == 0 {
+        0
+    } else if bits < 1 << (size - 1) {
+        bits as i32 - (1 << size) + 1
+    } else {
+        bits as i32
+    }
+}
+
+/// Reads the bits of entropy coded data, removing the stuffed zero bytes
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u32,
+    count: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data,
+            position: 0,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    fn bit(&mut self) -> Result<u32, Error> {
+        if self.count == 0 {
+            let byte = *self
+                .data
+                .get(self.position)
+                .ok_or_else(|| corrupt("Image data ends early"))?;
+            if byte == 0xff {
+                if self.data.get(self.position + 1) != Some(&0) {
+                    return Err(corrupt("Unexpected marker in image data"));
+                }
+                self.position += 1;
+            }
+            self.position += 1;
+            self.buffer = byte as u32;
+            self.count = 8;
+        }
+        self.count -= 1;
+        Ok(self.buffer >> self.count & 1)
+    }
+
+    fn bits(&mut self, count: u8) -> Result<u32, Error> {
+        (0..count).try_fold(0, |bits, _| Ok(bits << 1 | self.bit()?))
+    }
+
+    /// Skip the rest of the byte and the restart marker that follows
+    fn restart(&mut self) -> Result<(), Error> {
+        self.count = 0;
+        match self.data.get(self.position..self.position + 2) {
+            Some(&[0xff, 0xd0..=0xd7]) => {
+                self.position += 2;
+                Ok(())
+            }
+            _ => Err(corrupt("Missing restart marker")),
+        }
+    }
+}
+
+/// Transpose the entries of a block or quantization table
+fn transpose_block<T: Copy + Default>(block: &[T; 64]) -> [T; 64] {
+    let mut transposed = [T::default(); 64];
+    for (index, &value) in block.iter().enumerate() {
+        transposed[index % 8 * 8 + index / 8] = value;
+    }
+    transposed
+}
+
+/// Transform the coefficients of a block the same way as the block positions
+///
+/// Mirroring the pixels negates the odd frequencies along that axis, which the flips apply after
+/// the transpose.
+fn transform_block(block: &Block, transpose: bool, flip_h: bool, flip_v: bool) -> Block {
+    let mut block = if transpose {
+        transpose_block(block)
+    } else {
+        *block
+    };
+    for (index, coefficient) in block.iter_mut().enumerate() {
+        let (row, column) = (index / 8, index % 8);
+        if (flip_h && column % 2 == 1) != (flip_v && row % 2 == 1) {
+            *coefficient = -*coefficient;
+        }
+    }
+    block
+}
+
+/// Huffman table for encoding, the code and its length of every symbol
+struct EncodeTable {
+    /// Number of codes of every length from 1 to 16
+    counts: [u8; 16],
+    /// Symbols ordered by code
+    symbols: Vec<u8>,
+    codes: [(u16, u8); 256],
+}
+
+impl EncodeTable {
+    /// Build the optimal table for the symbol frequencies, like Annex K.2 of the JPEG
+    /// specification
+    fn optimal(frequencies: &[u32; 256]) -> Self {
+        // The extra symbol 256 reserves the all ones code, which JPEG doesn't allow
+        let mut frequency = [0u64; 257];
+        frequency[..256].copy_from_slice(&frequencies.map(u64::from));
+        frequency[256] = 1;
+        let mut code_size = [0usize; 257];
+        let mut others = [usize::MAX; 257];
+        loop {
+            // The least frequent symbols, preferring the higher ones on ties
+            let smallest = |skip: usize| {
+                (0..257)
+                    .filter(|&symbol| symbol != skip && frequency[symbol] > 0)
+                    .min_by_key(|&symbol| (frequency[symbol], usize::MAX - symbol))
+            };
+            let Some(c1) = smallest(usize::MAX) else {
+                break;
+            };
+            let Some(c2) = smallest(c1) else {
+                break;
+            };
+            frequency[c1] += frequency[c2];
+            frequency[c2] = 0;
+            let mut symbol = c1;
+            code_size[symbol] += 1;
+            while others[symbol] != usize::MAX {
+                symbol = others[symbol];
+                code_size[symbol] += 1;
+            }
+            others[symbol] = c2;
+            let mut symbol = c2;
+            code_size[symbol] += 1;
+            while others[symbol] != usize::MAX {
+                symbol = others[symbol];
+                code_size[symbol] += 1;
+            }
+        }
+
+        // Codes can be as long as there are symbols before limiting
+        let mut counts = [0usize; 258];
+        for &size in &code_size {
+            if size > 0 {
+                counts[size] += 1;
+            }
+        }
+        // Limit the codes to 16 bits, moving pairs of long codes up the tree
+        for length in (17..counts.len()).rev() {
+            while counts[length] > 0 {
+                let mut shorter = length - 2;
+                while counts[shorter] == 0 {
+                    shorter -= 1;
+                }
+                counts[length] -= 2;
+                counts[length - 1] += 1;
+                counts[shorter + 1] += 2;
+                counts[shorter] -= 1;
+            }
+        }
+        // Drop the reserved code, one of the longest
+        if let Some(longest) = (1..=16).rev().find(|&length| counts[length] > 0) {
+            counts[longest] -= 1;
+        }
+
+        let mut symbols = Vec::new();
+        for size in 1..counts.len() {
+            symbols.extend(
+                (0..256)
+                    .filter(|&symbol| code_size[symbol] == size)
+                    .map(|symbol| symbol as u8),
+            );
+        }
+        let counts: [u8; 16] = std::array::from_fn(|index| counts[index + 1] as u8);
+
+        let mut codes = [(0, 0); 256];
+        let (mut code, mut symbol_index) = (0u16, 0);
+        for length in 1..=16u8 {
+            for _ in 0..counts[length as usize - 1] {
+                codes[symbols[symbol_index] as usize] = (code, length);
+                code += 1;
+                symbol_index += 1;
+            }
+            code <<= 1;
+        }
+        EncodeTable {
+            counts,
+            symbols,
+            codes,
+        }
+    }
+}
+
+/// Number of bits of the magnitude of a coefficient
+fn magnitude_size(value: i32) -> u8 {
+    (32 - value.unsigned_abs().leading_zeros()) as u8
+}
+
+/// Code a block as Huffman symbols with their additional bits
+/// - block: Coefficients in natural order
+/// - prediction: DC of the previous block of the component, updated to this one
+/// - emit: Called with whether the symbol is AC, the symbol, and the additional bits and their
+///   count
+fn code_block(block: &Block, prediction: &mut i32, mut emit: impl FnMut(bool, u8, u32, u8)) {
+    let difference = block[0] as i32 - *prediction;
+    *prediction = block[0] as i32;
+    let size = magnitude_size(difference);
+    emit(false, size, additional_bits(difference, size), size);
+
+    let mut run = 0;
+    for &index in &ZIGZAG[1..] {
+        let value = block[index] as i32;
+        if value == 0 {
+            run += 1;
+            continue;
+        }
+        while run > 15 {
+            emit(true, 0xf0, 0, 0);
+            run -= 16;
+        }
+        let size = magnitude_size(value);
+        emit(
+            true,
+            (run << 4) as u8 | size,
+            additional_bits(value, size),
+            size,
+        );
+        run = 0;
+    }
+    if run > 0 {
+        emit(true, 0x00, 0, 0);
+    }
+}
+
+/// Additional bits of a coefficient, negative values being coded as one less
+fn additional_bits(value: i32, size: u8) -> u32 {
+    let value = if value < 0 { value - 1 } else { value };
+    value as u32 & ((1u32 << size) - 1)
+}
+
+/// Writes bits to entropy coded data, stuffing a zero byte after every 0xff
+struct BitWriter {
+    output: Vec<u8>,
+    buffer: u32,
+    count: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, bits: u32, count: u8) {
+        for shift in (0..count).rev() {
+            self.buffer = self.buffer << 1 | (bits >> shift & 1);
+            self.count += 1;
+            if self.count == 8 {
+                self.output.push(self.buffer as u8);
+                if self.buffer == 0xff {
+                    self.output.push(0);
+                }
+                self.buffer = 0;
+                self.count = 0;
+            }
+        }
+    }
+
+    /// Pad the last byte with one bits
+    fn flush(&mut self) {
+        if self.count > 0 {
+            self.write(0xff, 8 - self.count);
+        }
+    }
+}
+
+/// Append a segment with its marker and length
+fn push_segment(output: &mut Vec<u8>, marker: u8, data: &[u8]) {
+    output.extend_from_slice(&[0xff, marker]);
+    output.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+    output.extend_from_slice(data);
+}
+
+/// Write the transformed JPEG image with Huffman tables fitted to its coefficients
+fn write(jpeg: &Jpeg) -> Vec<u8> {
+    let frame = jpeg.frame.as_ref().expect("Parsed JPEG has a frame");
+    let scan_layouts = jpeg
+        .scans
+        .iter()
+        .map(|scan| {
+            let indexes = scan
+                .components
+                .iter()
+                .map(|&(index, ..)| index)
+                .collect::<Vec<_>>();
+            scan_blocks(frame, &indexes).0
+        })
+        .collect::<Vec<_>>();
+    let block = |scan: &Scan, (scan_index, x, y): (usize, usize, usize)| {
+        let component = &frame.components[scan.components[scan_index].0];
+        &component.blocks[y * component.blocks_wide + x]
+    };
+
+    // Count the symbols of every table over all scans
+    let mut frequencies = [[[0u32; 256]; 4]; 2];
+    for (scan, layout) in jpeg.scans.iter().zip(&scan_layouts) {
+        let mut predictions = vec![0; scan.components.len()];
+        for &position in layout {
+            let (_, dc_id, ac_id) = scan.components[position.0];
+            code_block(
+                block(scan, position),
+                &mut predictions[position.0],
+                |ac, symbol, _, _| {
+                    let id = if ac { ac_id } else { dc_id };
+                    frequencies[ac as usize][id as usize][symbol as usize] += 1;
+                },
+            );
+        }
+    }
+    let tables = frequencies.map(|class| {
+        class.map(|frequencies| {
+            frequencies
+                .iter()
+                .any(|&frequency| frequency > 0)
+                .then(|| EncodeTable::optimal(&frequencies))
+        })
+    });
+
+    let mut output = vec![0xff, 0xd8];
+    for segment in &jpeg.segments {
+        output.extend_from_slice(segment);
+    }
+
+    let mut dqt = Vec::new();
+    for (id, table) in jpeg.quant_tables.iter().enumerate() {
+        let Some(table) = table else { continue };
+        if !frame
+            .components
+            .iter()
+            .any(|component| component.quant_table as usize == id)
+        {
+            continue;
+        }
+        dqt.push((table.wide as u8) << 4 | id as u8);
+        for &index in &ZIGZAG {
+            if table.wide {
+                dqt.extend_from_slice(&table.values[index].to_be_bytes());
+            } else {
+                dqt.push(table.values[index] as u8);
+            }
+        }
+    }
+    push_segment(&mut output, 0xdb, &dqt);
+
+    let mut sof = vec![frame.precision];
+    sof.extend_from_slice(&(frame.height as u16).to_be_bytes());
+    sof.extend_from_slice(&(frame.width as u16).to_be_bytes());
+    sof.push(frame.components.len() as u8);
+    for component in &frame.components {
+        sof.extend_from_slice(&[
+            component.id,
+            component.h << 4 | component.v,
+            component.quant_table,
+        ]);
+    }
+    push_segment(&mut output, frame.marker, &sof);
+
+    let mut dht = Vec::new();
+    for (class, class_tables) in tables.iter().enumerate() {
+        for (id, table) in class_tables.iter().enumerate() {
+            let Some(table) = table else { continue };
+            dht.push((class as u8) << 4 | id as u8);
+            dht.extend_from_slice(&table.counts);
+            dht.extend_from_slice(&table.symbols);
+        }
+    }
+    push_segment(&mut output, 0xc4, &dht);
+
+    for (scan, layout) in jpeg.scans.iter().zip(&scan_layouts) {
+        let mut sos = vec![scan.components.len() as u8];
+        for &(index, dc_id, ac_id) in &scan.components {
+            sos.extend_from_slice(&[frame.components[index].id, dc_id << 4 | ac_id]);
+        }
+        sos.extend_from_slice(&[0, 63, 0]);
+        push_segment(&mut output, 0xda, &sos);
+
+        let mut writer = BitWriter {
+            output,
+            buffer: 0,
+            count: 0,
+        };
+        let mut predictions = vec![0; scan.components.len()];
+        for &position in layout {
+            let (_, dc_id, ac_id) = scan.components[position.0];
+            code_block(
+                block(scan, position),
+                &mut predictions[position.0],
+                |ac, symbol, bits, count| {
+                    let id = if ac { ac_id } else { dc_id };
+                    let table = tables[ac as usize][id as usize]
+                        .as_ref()
+                        .expect("Every coded symbol was counted");
+                    let (code, length) = table.codes[symbol as usize];
+                    writer.write(code as u32, length);
+                    writer.write(bits, count);
+                },
+            );
+        }
+        writer.flush();
+        output = writer.output;
+    }
+
+    output.extend_from_slice(&[0xff, 0xd9]);
+    output
+}
@@ -1,161 +1,4968 @@
-use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
-use image::codecs::jpeg::JpegEncoder;
+use error::{Error, ErrorCode};
+use image::codecs::bmp::BmpEncoder;
+use image::codecs::gif::{GifDecoder, Repeat};
+use image::codecs::ico::{IcoEncoder, IcoFrame};
+use image::codecs::png::{CompressionType, PngDecoder, PngEncoder};
+use image::codecs::qoi::QoiEncoder;
+use image::codecs::webp::WebPDecoder;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
 use image::{
-    AnimationDecoder, DynamicImage, EncodableLayout, ExtendedColorType, Frame, GenericImageView,
-    ImageEncoder, ImageFormat,
+    AnimationDecoder, Delay, DynamicImage, EncodableLayout, ExtendedColorType, Frame, ImageDecoder,
+    ImageFormat, ImageReader, RgbImage, Rgba,
 };
 use imagequant::{Image as QImage, RGBA};
-use std::io::{Cursor, Write};
+use metadata::Metadata;
+use std::borrow::Cow;
+use std::io::{Cursor, Seek, Write};
+use std::time::Duration;
+use tiff::encoder::colortype;
+use tiff::encoder::compression::{
+    Compression as TiffCompressionMethod, Deflate, Lzw, Uncompressed,
+};
 use wasm_bindgen::prelude::*;
 
-#[wasm_bindgen]
-extern "C" {
-    #[wasm_bindgen(js_namespace = console)]
-    pub fn log(s: &str);
+mod blurhash;
+mod error;
+mod jpeg_transform;
+mod metadata;
+mod metrics;
+mod palette;
+mod smartcrop;
+mod svg;
+#[cfg(test)]
+mod tests;
+mod webp;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    pub fn log(s: &str);
+}
+
+/// Filter used when resizing the image
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+    /// Average of the pixels each output pixel covers, for heavy reductions like thumbnails
+    Area,
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+            // Not an `image` filter, resizes check for it first and only other uses get here
+            ResizeFilter::Area => FilterType::Triangle,
+        }
+    }
+}
+
+/// How the image is fitted into the target size of `resize_to`
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResizeMode {
+    /// Keep the aspect ratio and fit inside the target size
+    #[default]
+    Fit,
+    /// Keep the aspect ratio, cover the target size and crop the overflow around the center
+    Fill,
+    /// Stretch to exactly the target size
+    Exact,
+}
+
+/// What happens when the image is larger than the padded canvas
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PadOverflow {
+    /// Crop the overflow around the center
+    #[default]
+    Crop,
+    /// Fail with an `INVALID_ARGUMENT` error
+    Fail,
+}
+
+/// Alpha mask applied to the image before encoding, like for avatars
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mask {
+    #[default]
+    None,
+    /// Crop the centered square and keep the circle inside it
+    Circle,
+    /// Round the corners with `corner_radius`
+    RoundedRect,
+}
+
+/// Where `watermark` places the overlay
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+    Center,
+}
+
+impl WatermarkPosition {
+    /// Left and top of an overlay of the given size on an image of the given size
+    fn offset(self, image: (u32, u32), overlay: (u32, u32)) -> (i64, i64) {
+        let (right, bottom) = (
+            image.0 as i64 - overlay.0 as i64,
+            image.1 as i64 - overlay.1 as i64,
+        );
+        match self {
+            WatermarkPosition::TopLeft => (0, 0),
+            WatermarkPosition::TopRight => (right, 0),
+            WatermarkPosition::BottomLeft => (0, bottom),
+            WatermarkPosition::BottomRight => (right, bottom),
+            WatermarkPosition::Center => (right / 2, bottom / 2),
+        }
+    }
+}
+
+/// Rotation or flip applied to the image after decoding
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transform {
+    #[default]
+    None,
+    /// Rotate 90 degrees clockwise
+    Rotate90,
+    Rotate180,
+    /// Rotate 270 degrees clockwise
+    Rotate270,
+    /// Mirror left to right
+    FlipH,
+    /// Mirror top to bottom
+    FlipV,
+}
+
+impl Transform {
+    /// Apply the transform to an image
+    fn apply(self, image: DynamicImage) -> DynamicImage {
+        match self {
+            Transform::None => image,
+            Transform::Rotate90 => image.rotate90(),
+            Transform::Rotate180 => image.rotate180(),
+            Transform::Rotate270 => image.rotate270(),
+            Transform::FlipH => image.fliph(),
+            Transform::FlipV => image.flipv(),
+        }
+    }
+}
+
+/// PNG compression preset, setting both the zlib level and the row filter
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PngCompression {
+    /// Fastest zlib level, with adaptive filtering to make up for the weaker deflate
+    Fast,
+    /// Default zlib level, no filtering
+    Default,
+    /// Best zlib level, no filtering
+    #[default]
+    Best,
+}
+
+impl PngCompression {
+    /// Zlib level, row filter and adaptive filter strategy of the preset
+    ///
+    /// Palette indexes rarely benefit from filtering, so only the fast preset uses it.
+    fn settings(self) -> (png::Compression, png::FilterType, png::AdaptiveFilterType) {
+        match self {
+            PngCompression::Fast => (
+                png::Compression::Fast,
+                png::FilterType::Sub,
+                png::AdaptiveFilterType::Adaptive,
+            ),
+            PngCompression::Default => (
+                png::Compression::Default,
+                png::FilterType::NoFilter,
+                png::AdaptiveFilterType::NonAdaptive,
+            ),
+            PngCompression::Best => (
+                png::Compression::Best,
+                png::FilterType::NoFilter,
+                png::AdaptiveFilterType::NonAdaptive,
+            ),
+        }
+    }
+}
+
+/// Speed and size trade-off applied by `CompressOptions.apply_preset`, setting several options at
+/// once
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Preset {
+    /// Fastest quantization, default PNG deflate without filter search and no JPEG Huffman
+    /// optimization, for throughput
+    Fast,
+    /// The default settings
+    #[default]
+    Balanced,
+    /// Slowest quantization for the best palette, with the best PNG deflate
+    Max,
+}
+
+/// JPEG chroma subsampling
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    /// 4:4:4, full color resolution, best for text and UI elements
+    Yuv444,
+    /// 4:2:2, half horizontal color resolution
+    Yuv422,
+    /// 4:2:0, half horizontal and vertical color resolution, best for photos
+    #[default]
+    Yuv420,
+}
+
+impl From<ChromaSubsampling> for jpeg_encoder::SamplingFactor {
+    fn from(subsampling: ChromaSubsampling) -> Self {
+        match subsampling {
+            ChromaSubsampling::Yuv444 => jpeg_encoder::SamplingFactor::R_4_4_4,
+            ChromaSubsampling::Yuv422 => jpeg_encoder::SamplingFactor::R_4_2_2,
+            ChromaSubsampling::Yuv420 => jpeg_encoder::SamplingFactor::R_4_2_0,
+        }
+    }
+}
+
+/// Compression of TIFF output, all lossless
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TiffCompression {
+    /// Uncompressed, readable by every TIFF reader
+    None,
+    /// LZW, widely supported by scanning and OCR software
+    Lzw,
+    /// Deflate, usually the smallest
+    #[default]
+    Deflate,
+}
+
+/// Rendering intent of the sRGB chunk of PNG output, telling how to map colors outside the
+/// gamut of the display
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RenderingIntent {
+    /// Keep the overall look, best for photos
+    #[default]
+    Perceptual,
+    /// Keep colors inside the gamut exactly, relative to the white point
+    RelativeColorimetric,
+    /// Keep colors vivid, best for charts and graphics
+    Saturation,
+    /// Keep colors exactly, including the white point
+    AbsoluteColorimetric,
+}
+
+impl From<RenderingIntent> for png::SrgbRenderingIntent {
+    fn from(intent: RenderingIntent) -> Self {
+        match intent {
+            RenderingIntent::Perceptual => png::SrgbRenderingIntent::Perceptual,
+            RenderingIntent::RelativeColorimetric => png::SrgbRenderingIntent::RelativeColorimetric,
+            RenderingIntent::Saturation => png::SrgbRenderingIntent::Saturation,
+            RenderingIntent::AbsoluteColorimetric => png::SrgbRenderingIntent::AbsoluteColorimetric,
+        }
+    }
+}
+
+/// Compression options for `compress_detailed`
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct CompressOptions {
+    /// Compression quality (0-100, lower means worse quality)
+    pub quality: u8,
+    /// Quality of JPEG output in place of `quality` (0-100, optional)
+    pub jpeg_quality: Option<u8>,
+    /// Quantization quality of WebP output in place of `quality` (0-100, optional)
+    pub webp_quality: Option<u8>,
+    /// Quantization quality of PNG output in place of `quality` (0-100, optional)
+    pub png_quality: Option<u8>,
+    /// Size scaling factor (0-1, smaller means smaller size)
+    pub resize_percent: f32,
+    /// Longest side of the output in pixels (0 for no limit), used instead of `resize_percent`;
+    /// smaller images aren't enlarged
+    pub max_dimension: u32,
+    /// Resize filter
+    pub filter: ResizeFilter,
+    /// Resize in linear light instead of sRGB, which keeps fine bright detail from darkening but
+    /// takes a float conversion of every pixel
+    pub linear_resize: bool,
+    /// Halve the image repeatedly before the final resize when shrinking below half the size,
+    /// which is faster on large reductions
+    pub stepped_resize: bool,
+    /// Rotation or flip, applied after the EXIF orientation
+    pub transform: Transform,
+    /// Left edge of the crop rectangle applied right after the transform
+    pub crop_x: u32,
+    /// Top edge of the crop rectangle
+    pub crop_y: u32,
+    /// Width of the crop rectangle (0 to not crop), clamped to the image
+    pub crop_width: u32,
+    /// Height of the crop rectangle (0 to not crop), clamped to the image
+    pub crop_height: u32,
+    /// Aspect ratio width to center-crop to before resizing (0 to not crop)
+    pub aspect_width: u32,
+    /// Aspect ratio height to center-crop to before resizing (0 to not crop)
+    pub aspect_height: u32,
+    /// Target width (0 for auto from the aspect ratio), used instead of `resize_percent` when
+    /// `width` or `height` is set
+    pub width: u32,
+    /// Target height (0 for auto from the aspect ratio)
+    pub height: u32,
+    /// How the image is fitted into the target size
+    pub resize_mode: ResizeMode,
+    /// Width of the canvas the image is centered on after resizing (0 keeps the image width)
+    pub pad_width: u32,
+    /// Height of the canvas the image is centered on after resizing (0 keeps the image height)
+    pub pad_height: u32,
+    /// Color of the padding, as a hex string like `#ffffff` or `#00000000` (transparent when not
+    /// set)
+    #[wasm_bindgen(getter_with_clone)]
+    pub pad_color: Option<String>,
+    /// What happens when the image is larger than the padded canvas
+    pub pad_overflow: PadOverflow,
+    /// Alpha mask applied after resizing and padding, leaving the outside transparent (JPEG
+    /// blends it onto `background`)
+    pub mask: Mask,
+    /// Corner radius in pixels of the `RoundedRect` mask, clamped to half the shorter side
+    pub corner_radius: u32,
+    /// Drop EXIF and ICC profile instead of carrying them into the output
+    pub strip_metadata: bool,
+    /// Maximum number of palette colors for PNG, GIF and WebP quantization (2-256)
+    pub max_colors: u16,
+    /// Dithering level of quantization (0-1, 0 disables dithering)
+    pub dither_level: f32,
+    /// Minimum quantization quality (0-100), below which compression fails instead of
+    /// returning a poor looking image
+    pub min_quality: u8,
+    /// Quantization speed (1-10, 1 is slowest with the best palette, 10 is fastest)
+    pub speed: u8,
+    /// PNG compression preset
+    pub png_compression: PngCompression,
+    /// Stretch the levels after resizing so the darkest pixels become black and the brightest
+    /// white, like for underexposed scans
+    pub auto_levels: bool,
+    /// Amount added to every color channel after resizing (-255 to 255, 0 keeps the brightness)
+    pub brightness: i32,
+    /// Contrast factor applied after resizing (1 keeps the contrast, 0 makes the image gray and
+    /// larger values increase it)
+    pub contrast: f32,
+    /// Unsharp mask radius applied after resizing to restore detail lost by downscaling (0 to not
+    /// sharpen, clamped to 5; 0.5-1.5 is typical)
+    pub sharpen: f32,
+    /// Gaussian blur sigma applied after resizing, like for redacting faces or plates (0 to not
+    /// blur); slow for large values
+    pub blur: f32,
+    /// Left edge of the blurred rectangle, in pixels of the resized image
+    pub blur_x: u32,
+    /// Top edge of the blurred rectangle
+    pub blur_y: u32,
+    /// Width of the blurred rectangle (0 to blur the whole image), clamped to the image
+    pub blur_width: u32,
+    /// Height of the blurred rectangle (0 to blur the whole image), clamped to the image
+    pub blur_height: u32,
+    /// Convert to grayscale before encoding
+    pub grayscale: bool,
+    /// Reduce every color channel to this many evenly spaced levels (2-255) after resizing, for a
+    /// posterized look that also compresses better (not set to keep all levels)
+    pub posterize: Option<u8>,
+    /// Keep every n-th frame of an animation, giving it the delays of the dropped frames (0 and
+    /// 1 keep all frames)
+    pub frame_skip: u8,
+    /// Number of times an animation repeats after playing once (0 loops forever, not set keeps
+    /// the loop count of the input)
+    pub repeat: Option<u16>,
+    /// Background color that transparent pixels are blended onto for JPEG output and
+    /// `drop_alpha`, as a hex string like `#ffffff` (white when not set)
+    #[wasm_bindgen(getter_with_clone)]
+    pub background: Option<String>,
+    /// Make pixels with alpha below this fully transparent and the rest fully opaque, after
+    /// resizing and masking, for sprite masks and GIF's 1-bit transparency (not set to keep alpha)
+    pub alpha_threshold: Option<u8>,
+    /// Blend transparent pixels onto `background` and drop the alpha channel, whatever the output
+    /// format, so still PNGs are written as RGB instead of indexed
+    pub drop_alpha: bool,
+    /// JPEG chroma subsampling
+    pub chroma_subsampling: ChromaSubsampling,
+    /// Write progressive JPEG, which renders incrementally while loading
+    pub progressive: bool,
+    /// Build Huffman tables fitted to the JPEG image, which makes it smaller at no quality loss but
+    /// takes a second pass over the image
+    pub optimize_huffman: bool,
+    /// Decode the output and compare it with the input to report PSNR and SSIM
+    pub measure_quality: bool,
+    /// Quantize all GIF frames to one global palette instead of a palette per frame, which is
+    /// smaller and keeps colors from flickering between frames
+    pub gif_global_palette: bool,
+    /// Store only the pixels that changed from the previous frame in opaque GIF animations,
+    /// leaving the rest transparent so the previous frame shows through
+    pub gif_delta_frames: bool,
+    /// Write a GIF with a single frame as PNG, which compresses still images better
+    pub gif_still_as_png: bool,
+    /// Rendering intent of the sRGB chunk written into PNG output without an ICC profile (not set
+    /// to leave the chunk out)
+    pub srgb_intent: Option<RenderingIntent>,
+    /// Resolution in dots per inch to write into PNG and JPEG output (not set keeps the input's)
+    pub dpi: Option<u16>,
+    /// Write interlaced (Adam7) PNG, which renders progressively while loading but is larger
+    pub interlace: bool,
+    /// Keyword and text pairs written into PNG output, added with `add_png_text`
+    #[wasm_bindgen(skip)]
+    pub png_text: Vec<(String, String)>,
+    /// Encode WebP with its exact colors, ignoring the quality, instead of quantizing it first
+    pub webp_lossless: bool,
+    /// Near-lossless level of `webp_lossless` output (0-100, 100 keeps the pixels exact and lower
+    /// values round more pixels outside of smooth areas)
+    pub near_lossless: u8,
+    /// Compression of TIFF output
+    pub tiff_compression: TiffCompression,
+    /// Return inputs of at most this many bytes as they are, without encoding, unless their
+    /// pixels are edited (0 to always encode)
+    pub skip_below_bytes: usize,
+    /// Return JPEG inputs saved at or below the requested quality and indexed PNG inputs with at
+    /// most `max_colors` colors as they are, without encoding, unless their pixels are edited
+    pub skip_optimized: bool,
+    /// Always return the re-encoded output, even when it is larger than the input
+    pub force: bool,
+    /// Width SVG input is rendered at (0 for auto from the aspect ratio, or the size of the
+    /// document when `svg_height` is 0 too)
+    pub svg_width: u32,
+    /// Height SVG input is rendered at (0 for auto from the aspect ratio)
+    pub svg_height: u32,
+    /// Largest width times height of an input image, checked before decoding so huge images
+    /// fail instead of running out of memory, and of all frames of an animation together
+    /// (0 for no limit)
+    pub max_pixels: u32,
+    /// Called with `(done, total)` after each GIF or APNG frame is quantized, like for a progress
+    /// bar
+    #[wasm_bindgen(getter_with_clone)]
+    pub on_progress: Option<js_sys::Function>,
+}
+
+#[wasm_bindgen]
+impl CompressOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> CompressOptions {
+        CompressOptions::default()
+    }
+
+    /// Set the quantization speed, PNG compression and JPEG Huffman optimization of a preset,
+    /// leaving the other options as they are
+    pub fn apply_preset(&mut self, preset: Preset) {
+        let (speed, png_compression, optimize_huffman) = match preset {
+            // The adaptive filtering of `PngCompression::Fast` makes palette images much larger
+            // while saving little time over the default level
+            Preset::Fast => (10, PngCompression::Default, false),
+            Preset::Balanced => (4, PngCompression::Best, true),
+            Preset::Max => (1, PngCompression::Best, true),
+        };
+        self.speed = speed;
+        self.png_compression = png_compression;
+        self.optimize_huffman = optimize_huffman;
+    }
+
+    /// Add a text chunk to PNG output, like an `Author` or `Copyright` notice
+    /// - keyword: Name of the text, 1-79 Latin-1 characters
+    /// - text: Text, written as `tEXt` when it's Latin-1 and as UTF-8 `iTXt` otherwise
+    pub fn add_png_text(&mut self, keyword: String, text: String) {
+        self.png_text.push((keyword, text));
+    }
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        CompressOptions {
+            quality: 80,
+            jpeg_quality: None,
+            webp_quality: None,
+            png_quality: None,
+            resize_percent: 1.0,
+            max_dimension: 0,
+            filter: ResizeFilter::default(),
+            linear_resize: false,
+            stepped_resize: false,
+            transform: Transform::default(),
+            crop_x: 0,
+            crop_y: 0,
+            crop_width: 0,
+            crop_height: 0,
+            aspect_width: 0,
+            aspect_height: 0,
+            width: 0,
+            height: 0,
+            resize_mode: ResizeMode::default(),
+            pad_width: 0,
+            pad_height: 0,
+            pad_color: None,
+            pad_overflow: PadOverflow::default(),
+            mask: Mask::default(),
+            corner_radius: 0,
+            strip_metadata: true,
+            max_colors: 256,
+            dither_level: 1.0,
+            min_quality: 0,
+            speed: 4,
+            png_compression: PngCompression::default(),
+            auto_levels: false,
+            brightness: 0,
+            contrast: 1.0,
+            sharpen: 0.0,
+            blur: 0.0,
+            blur_x: 0,
+            blur_y: 0,
+            blur_width: 0,
+            blur_height: 0,
+            grayscale: false,
+            posterize: None,
+            frame_skip: 1,
+            repeat: None,
+            background: None,
+            alpha_threshold: None,
+            drop_alpha: false,
+            chroma_subsampling: ChromaSubsampling::default(),
+            progressive: false,
+            optimize_huffman: true,
+            measure_quality: false,
+            gif_global_palette: false,
+            gif_delta_frames: true,
+            gif_still_as_png: false,
+            srgb_intent: Some(RenderingIntent::default()),
+            dpi: None,
+            interlace: false,
+            png_text: Vec::new(),
+            webp_lossless: false,
+            near_lossless: 100,
+            tiff_compression: TiffCompression::default(),
+            skip_below_bytes: 0,
+            skip_optimized: false,
+            force: false,
+            svg_width: 0,
+            svg_height: 0,
+            max_pixels: DEFAULT_MAX_PIXELS,
+            on_progress: None,
+        }
+    }
+}
+
+/// Result of `compress_detailed`
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Clone, Debug)]
+pub struct CompressionResult {
+    /// Encoded image data
+    pub bytes: Vec<u8>,
+    /// Size of the input in bytes
+    pub original_size: usize,
+    /// Size of `bytes`
+    pub compressed_size: usize,
+    /// Width of the output image
+    pub width: u32,
+    /// Height of the output image
+    pub height: u32,
+    /// Output format: png, jpeg, webp, gif, qoi, bmp or tiff
+    pub format: String,
+    /// Quality used to encode the output
+    pub quality: u8,
+    /// Quality (0-100) the quantization actually achieved, when the output was quantized
+    pub quantization_quality: Option<u8>,
+    /// Whether the input had more than 8 bits per channel, which the output doesn't keep
+    pub bit_depth_reduced: bool,
+    /// Peak signal to noise ratio of the output in dB, when `measure_quality` is set
+    pub psnr: Option<f64>,
+    /// Structural similarity of the output luma (0-1), when `measure_quality` is set
+    pub ssim: Option<f64>,
+    /// Whether the input was returned as is, because the compressed output was larger or
+    /// `skip_below_bytes` or `skip_optimized` skipped the encoding
+    pub returned_original: bool,
+    /// Encoded size in bytes of every frame of GIF output, with its header and color table
+    /// (empty for other formats)
+    pub frame_sizes: Vec<u32>,
+    /// Number of palette colors of every frame of GIF output, the global palette size when it is
+    /// shared (empty for other formats)
+    pub frame_colors: Vec<u16>,
+    /// Number of times the output animation plays, 0 for forever (not set for still images)
+    pub plays: Option<u32>,
+    /// What the output lost or fell back to, like transparency blended away for JPEG or the
+    /// input returned as it was smaller (empty when nothing happened)
+    pub warnings: Vec<String>,
+}
+
+/// Compress image
+/// - bytes: Image byte array (Uint8Array from frontend)
+/// - quality: Compression quality (0-100, lower means worse quality)
+/// - resize_percent: Size scaling factor (0-1, smaller means smaller size)
+/// - filter: Resize filter (optional, defaults to Lanczos3)
+/// - target_format: Output format: png, jpeg, webp, gif, qoi, bmp or tiff (optional, defaults to the
+///   input format)
+#[wasm_bindgen]
+pub fn compress(
+    bytes: &[u8],
+    quality: u8,
+    resize_percent: f32,
+    filter: Option<ResizeFilter>,
+    target_format: Option<String>,
+) -> Result<Vec<u8>, Error> {
+    let options = CompressOptions {
+        quality,
+        resize_percent,
+        filter: filter.unwrap_or_default(),
+        ..CompressOptions::default()
+    };
+    let Some(target_format) = target_format else {
+        return Ok(compress_detailed(bytes, &options)?.bytes);
+    };
+    let format = parse_format(&target_format)?;
+    validate_options(&options)?;
+
+    let source = prepare_source(bytes, &options)?;
+    let output = encode_source(&source, format, &options)?;
+
+    Ok(build_result(bytes, &source, format, output, options.quality_for(format)).bytes)
+}
+
+/// Compress image and report sizes, dimensions and format of the result
+/// - bytes: Image byte array (Uint8Array from frontend)
+/// - options: Compression options
+#[wasm_bindgen]
+pub fn compress_detailed(
+    bytes: &[u8],
+    options: &CompressOptions,
+) -> Result<CompressionResult, Error> {
+    validate_options(options)?;
+
+    let source = prepare_source(bytes, options)?;
+    let quality = options.quality_for(source.format);
+    let mut result = if is_already_optimized(bytes, &source, options) {
+        original_result(bytes, &source, quality)
+    } else {
+        let output = encode_source(&source, source.format, options)?;
+        build_result(bytes, &source, source.format, output, quality)
+    };
+    if options.measure_quality {
+        measure_quality(&source, &mut result, options)?;
+    }
+    Ok(result)
+}
+
+/// Compress image and return it as a data URL, like `data:image/png;base64,...`, to use as the
+/// `src` of an image element
+/// - bytes: Image byte array (Uint8Array from frontend)
+/// - quality: Compression quality (0-100, lower means worse quality)
+/// - resize_percent: Size scaling factor (0-1, smaller means smaller size)
+#[wasm_bindgen]
+pub fn compress_to_data_url(
+    bytes: &[u8],
+    quality: u8,
+    resize_percent: f32,
+) -> Result<String, Error> {
+    let options = CompressOptions {
+        quality,
+        resize_percent,
+        ..CompressOptions::default()
+    };
+    let result = compress_detailed(bytes, &options)?;
+    let mime_type = parse_format(&result.format)?.to_mime_type();
+    Ok(format!(
+        "data:{mime_type};base64,{}",
+        base64_encode(&result.bytes)
+    ))
+}
+
+/// Compress an image given as base64 or a data URL, like the result of `canvas.toDataURL()`
+/// - base64: Base64 image data, optionally with a `data:...;base64,` prefix
+/// - quality: Compression quality (0-100, lower means worse quality)
+/// - resize_percent: Size scaling factor (0-1, smaller means smaller size)
+#[wasm_bindgen]
+pub fn compress_base64(base64: &str, quality: u8, resize_percent: f32) -> Result<Vec<u8>, Error> {
+    let data = match base64.strip_prefix("data:") {
+        Some(data_url) => data_url
+            .split_once(";base64,")
+            .map(|(_, data)| data)
+            .ok_or_else(|| Error::new(ErrorCode::InvalidArgument, "Data URL is not base64"))?,
+        None => base64,
+    };
+    let bytes = base64_decode(data)?;
+    compress(&bytes, quality, resize_percent, None, None)
+}
+
+/// Encode bytes as standard base64, with padding
+fn base64_encode(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        // Three bytes make four 6-bit digits, a shorter last chunk is padded with `=`
+        let group = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |group, (index, &byte)| {
+                group | (byte as u32) << (16 - 8 * index)
+            });
+        for digit in 0..4 {
+            if digit <= chunk.len() {
+                encoded.push(DIGITS[(group >> (18 - 6 * digit) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decode standard base64, with or without padding, skipping whitespace
+fn base64_decode(base64: &str) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::new(ErrorCode::InvalidArgument, "Invalid base64");
+    let digits = base64
+        .trim_end_matches(|char: char| char == '=' || char.is_ascii_whitespace())
+        .bytes()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .map(|byte| match byte {
+            b'A'..=b'Z' => Ok(byte - b'A'),
+            b'a'..=b'z' => Ok(byte - b'a' + 26),
+            b'0'..=b'9' => Ok(byte - b'0' + 52),
+            b'+' => Ok(62),
+            b'/' => Ok(63),
+            _ => Err(invalid()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // A single digit left over can't hold a whole byte
+    if digits.len() % 4 == 1 {
+        return Err(invalid());
+    }
+
+    let mut bytes = Vec::with_capacity(digits.len() * 3 / 4);
+    for chunk in digits.chunks(4) {
+        let group = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |group, (index, &digit)| {
+                group | (digit as u32) << (18 - 6 * index)
+            });
+        for index in 0..chunk.len() - 1 {
+            bytes.push((group >> (16 - 8 * index)) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+/// Decode the output of a result and compare it with the encoded image to set PSNR and SSIM
+/// - source: Image that was encoded
+/// - result: Result to measure
+/// - options: Compression options
+///
+/// Both images are blended onto the JPEG background first, transparent pixels can have any color.
+/// Animations are compared by their first frame.
+fn measure_quality(
+    source: &Source,
+    result: &mut CompressionResult,
+    options: &CompressOptions,
+) -> Result<(), Error> {
+    let background = options.background_color()?;
+    let reference = flatten_alpha(source.image.clone(), background).into_rgb8();
+    let output = decode_source(&result.bytes, true, options.max_pixels)?.image;
+    let output = flatten_alpha(output, background).into_rgb8();
+    if reference.dimensions() != output.dimensions() {
+        return Err(Error::new(
+            ErrorCode::EncodeFailed,
+            "Output size differs from the input",
+        ));
+    }
+
+    result.psnr = Some(metrics::psnr(&reference, &output));
+    result.ssim = Some(metrics::ssim(&reference, &output));
+    Ok(())
+}
+
+/// Resize image to a target size and compress it
+/// - bytes: Image byte array (Uint8Array from frontend)
+/// - width: Target width (0 for auto from the aspect ratio)
+/// - height: Target height (0 for auto from the aspect ratio)
+/// - mode: How the image is fitted into the target size (optional, defaults to Fit)
+/// - quality: Compression quality (0-100, lower means worse quality)
+#[wasm_bindgen]
+pub fn resize_to(
+    bytes: &[u8],
+    width: u32,
+    height: u32,
+    mode: Option<ResizeMode>,
+    quality: u8,
+) -> Result<Vec<u8>, Error> {
+    let options = CompressOptions {
+        quality,
+        width,
+        height,
+        resize_mode: mode.unwrap_or_default(),
+        ..CompressOptions::default()
+    };
+    Ok(compress_detailed(bytes, &options)?.bytes)
+}
+
+/// Crop the center of an image to an aspect ratio and compress it
+/// - bytes: Image byte array (Uint8Array from frontend)
+/// - aspect_width: Aspect ratio width, like 16 for 16:9
+/// - aspect_height: Aspect ratio height, like 9 for 16:9
+/// - quality: Compression quality (0-100, lower means worse quality)
+/// - resize_percent: Size scaling factor (0-1, smaller means smaller size), applied after cropping
+#[wasm_bindgen]
+pub fn crop_center(
+    bytes: &[u8],
+    aspect_width: u32,
+    aspect_height: u32,
+    quality: u8,
+    resize_percent: f32,
+) -> Result<Vec<u8>, Error> {
+    if aspect_width == 0 || aspect_height == 0 {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "Aspect ratio must not be zero",
+        ));
+    }
+    let options = CompressOptions {
+        quality,
+        resize_percent,
+        aspect_width,
+        aspect_height,
+        ..CompressOptions::default()
+    };
+    Ok(compress_detailed(bytes, &options)?.bytes)
+}
+
+/// Crop the most detailed region of an image to an aspect ratio, resize and compress it, like
+/// for thumbnails where a center crop would cut off the subject
+/// - bytes: Image byte array (Uint8Array from frontend)
+/// - aspect_width: Aspect ratio width, like 16 for 16:9
+/// - aspect_height: Aspect ratio height, like 9 for 16:9
+/// - width: Output width (0 for auto from the aspect ratio)
+/// - height: Output height (0 for auto from the aspect ratio)
+/// - quality: Compression quality (0-100, lower means worse quality)
+///
+/// The crop window slides over the image and keeps the position covering the most edges, by
+/// the luma gradient. Animations are cropped at the position found on their first frame.
+#[wasm_bindgen]
+pub fn smart_crop(
+    bytes: &[u8],
+    aspect_width: u32,
+    aspect_height: u32,
+    width: u32,
+    height: u32,
+    quality: u8,
+) -> Result<Vec<u8>, Error> {
+    if aspect_width == 0 || aspect_height == 0 {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "Aspect ratio must not be zero",
+        ));
+    }
+    let options = CompressOptions {
+        quality,
+        ..CompressOptions::default()
+    };
+    validate_options(&options)?;
+
+    let source = prepare_source(bytes, &options)?;
+    let (crop_width, crop_height) = aspect_crop_size(
+        source.image.width(),
+        source.image.height(),
+        aspect_width,
+        aspect_height,
+    );
+    let (left, top) = smartcrop::find_crop(&source.image, crop_width, crop_height);
+    let mut source = map_source(source, |image| {
+        image.crop_imm(left, top, crop_width, crop_height)
+    });
+    if width > 0 || height > 0 {
+        source = map_source(source, |image| {
+            resize_image_to(image, width, height, ResizeMode::Fill, options.resampling())
+        });
+    }
+    let output = encode_source(&source, source.format, &options)?;
+
+    Ok(build_result(
+        bytes,
+        &source,
+        source.format,
+        output,
+        options.quality_for(source.format),
+    )
+    .bytes)
+}
+
+/// Fit an image inside a size and pad it to exactly that size, like for square product
+/// thumbnails
+/// - bytes: Image byte array (Uint8Array from frontend)
+/// - width: Output width
+/// - height: Output height
+/// - color: Hex color of the padding, like `#ffffff` (optional, transparent when not set, which
+///   JPEG blends onto white)
+/// - quality: Compression quality (0-100, lower means worse quality)
+#[wasm_bindgen]
+pub fn pad_to(
+    bytes: &[u8],
+    width: u32,
+    height: u32,
+    color: Option<String>,
+    quality: u8,
+) -> Result<Vec<u8>, Error> {
+    if width == 0 || height == 0 {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "Width and height must not be zero",
+        ));
+    }
+    let options = CompressOptions {
+        quality,
+        width,
+        height,
+        resize_mode: ResizeMode::Fit,
+        pad_width: width,
+        pad_height: height,
+        pad_color: color,
+        ..CompressOptions::default()
+    };
+    Ok(compress_detailed(bytes, &options)?.bytes)
+}
+
+/// Stamp an overlay image, like a logo, onto an image and compress it
+/// - base_bytes: Image byte array (Uint8Array from frontend)
+/// - overlay_bytes: Byte array of the overlay image, drawn at its own size
+/// - position: Where the overlay is placed
+/// - opacity: Opacity of the overlay (0-1), multiplied with its alpha channel
+/// - quality: Compression quality (0-100, lower means worse quality)
+/// - target_format: Output format: png, jpeg, webp, gif, qoi, bmp or tiff (optional, defaults to the
+///   input format)
+///
+/// An overlay larger than the image is clipped. Animations get the overlay on every frame.
+#[wasm_bindgen]
+pub fn watermark(
+    base_bytes: &[u8],
+    overlay_bytes: &[u8],
+    position: WatermarkPosition,
+    opacity: f32,
+    quality: u8,
+    target_format: Option<String>,
+) -> Result<Vec<u8>, Error> {
+    if !(0.0..=1.0).contains(&opacity) {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "opacity must be between 0 and 1",
+        ));
+    }
+    let options = CompressOptions {
+        quality,
+        ..CompressOptions::default()
+    };
+    validate_options(&options)?;
+
+    let mut overlay = decode_source(overlay_bytes, true, DEFAULT_MAX_PIXELS)?
+        .image
+        .into_rgba8();
+    for pixel in overlay.pixels_mut() {
+        pixel.0[3] = (pixel.0[3] as f32 * opacity).round() as u8;
+    }
+    let source = prepare_source(base_bytes, &options)?;
+    let format = match target_format {
+        Some(name) => parse_format(&name)?,
+        None => source.format,
+    };
+
+    let source = map_source(source, |image| {
+        let mut image = image.into_rgba8();
+        let (left, top) = position.offset(image.dimensions(), overlay.dimensions());
+        image::imageops::overlay(&mut image, &overlay, left, top);
+        DynamicImage::from(image)
+    });
+    let output = encode_source(&source, format, &options)?;
+
+    Ok(build_result(
+        base_bytes,
+        &source,
+        format,
+        output,
+        options.quality_for(format),
+    )
+    .bytes)
+}
+
+/// Outcome of one image of `compress_batch`, holding either a result or an error message
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Clone, Debug)]
+pub struct BatchResult {
+    /// Compression result, when the image was compressed
+    pub result: Option<CompressionResult>,
+    /// Error message, when the image failed
+    pub error: Option<String>,
+    /// Error code, like `DECODE_FAILED`, when the image failed
+    pub error_code: Option<String>,
+}
+
+/// Compress multiple images in one call
+/// - images: Image byte arrays (Array of Uint8Array from frontend)
+/// - quality: Compression quality (0-100, lower means worse quality)
+/// - resize_percent: Size scaling factor (0-1, smaller means smaller size)
+///
+/// A failing image doesn't abort the batch, its entry carries the error instead.
+#[wasm_bindgen]
+pub fn compress_batch(
+    images: Vec<js_sys::Uint8Array>,
+    quality: u8,
+    resize_percent: f32,
+) -> Vec<BatchResult> {
+    let options = CompressOptions {
+        quality,
+        resize_percent,
+        ..CompressOptions::default()
+    };
+    images
+        .iter()
+        .map(|image| match compress_detailed(&image.to_vec(), &options) {
+            Ok(result) => BatchResult {
+                result: Some(result),
+                error: None,
+                error_code: None,
+            },
+            Err(error) => BatchResult {
+                result: None,
+                error: Some(error.message().to_string()),
+                error_code: Some(error.code().to_string()),
+            },
+        })
+        .collect()
+}
+
+/// Convert image to another format
+/// - bytes: Image byte array (Uint8Array from frontend)
+/// - target_format: Output format: png, jpeg, webp, gif, qoi, bmp or tiff
+/// - quality: Compression quality (0-100, lower means worse quality)
+/// - resize_percent: Size scaling factor (0-1, smaller means smaller size)
+/// - background: Hex color that transparency is blended onto for JPEG (optional, defaults to white)
+#[wasm_bindgen]
+pub fn transcode(
+    bytes: &[u8],
+    target_format: &str,
+    quality: u8,
+    resize_percent: f32,
+    background: Option<String>,
+) -> Result<Vec<u8>, Error> {
+    let format = parse_format(target_format)?;
+    let options = CompressOptions {
+        quality,
+        resize_percent,
+        background,
+        ..CompressOptions::default()
+    };
+    validate_options(&options)?;
+
+    let source = prepare_source(bytes, &options)?;
+    let output = encode_source(&source, format, &options)?;
+
+    Ok(build_result(bytes, &source, format, output, options.quality_for(format)).bytes)
+}
+
+/// Convert a GIF to an APNG
+/// - bytes: GIF byte array (Uint8Array from frontend)
+/// - quality: Compression quality (0-100, lower means worse quality)
+/// - resize_percent: Size scaling factor (0-1, smaller means smaller size)
+///
+/// Frame delays, the loop count and transparency are kept, and the palette has full alpha, so
+/// edges smoothed by resizing stay soft instead of fringing like GIF's 1-bit transparency. The
+/// APNG is returned even when larger than the GIF, and a single frame GIF becomes a still PNG.
+#[wasm_bindgen]
+pub fn gif_to_apng(bytes: &[u8], quality: u8, resize_percent: f32) -> Result<Vec<u8>, Error> {
+    if image::guess_format(bytes)? != ImageFormat::Gif {
+        return Err(Error::new(
+            ErrorCode::UnsupportedFormat,
+            "gif_to_apng expects a GIF image",
+        ));
+    }
+    let options = CompressOptions {
+        quality,
+        resize_percent,
+        ..CompressOptions::default()
+    };
+    validate_options(&options)?;
+
+    let source = prepare_source(bytes, &options)?;
+    Ok(encode_source(&source, ImageFormat::Png, &options)?.bytes)
+}
+
+/// Encode image as PNG, JPEG and WebP and keep the smallest
+/// - bytes: Image byte array (Uint8Array from frontend)
+/// - quality: Compression quality (0-100, lower means worse quality)
+/// - resize_percent: Size scaling factor (0-1, smaller means smaller size)
+///
+/// JPEG is skipped for animations and for images with transparent pixels, as it can't represent
+/// them. The result's `format` tells which format won.
+#[wasm_bindgen]
+pub fn compress_auto(
+    bytes: &[u8],
+    quality: u8,
+    resize_percent: f32,
+) -> Result<CompressionResult, Error> {
+    let options = CompressOptions {
+        quality,
+        resize_percent,
+        ..CompressOptions::default()
+    };
+    validate_options(&options)?;
+
+    let source = prepare_source(bytes, &options)?;
+    let mut formats = vec![ImageFormat::Png, ImageFormat::WebP];
+    if source.frames.len() <= 1 && is_opaque(&source.image) {
+        formats.push(ImageFormat::Jpeg);
+    }
+
+    let mut smallest: Option<CompressionResult> = None;
+    for format in formats {
+        let output = encode_source(&source, format, &options)?;
+        let result = build_result(bytes, &source, format, output, options.quality_for(format));
+        if smallest
+            .as_ref()
+            .is_none_or(|smallest| result.compressed_size < smallest.compressed_size)
+        {
+            smallest = Some(result);
+        }
+    }
+
+    smallest.ok_or_else(|| Error::new(ErrorCode::EncodeFailed, "Failed to compress image"))
+}
+
+/// Compress an image at several widths, like for the `srcset` of a responsive image
+/// - bytes: Image byte array (Uint8Array from frontend)
+/// - widths: Width of every output, the height follows the aspect ratio
+/// - quality: Compression quality (0-100, lower means worse quality)
+/// - target_format: Output format: png, jpeg, webp, gif, qoi, bmp or tiff (optional, defaults to the
+///   input format)
+///
+/// The image is decoded once and resized from the full size for every width, with one result
+/// per width in the same order.
+#[wasm_bindgen]
+pub fn responsive_set(
+    bytes: &[u8],
+    widths: Vec<u32>,
+    quality: u8,
+    target_format: Option<String>,
+) -> Result<Vec<CompressionResult>, Error> {
+    if widths.is_empty() {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "At least one width is required",
+        ));
+    }
+    if widths.contains(&0) {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "Widths must not be zero",
+        ));
+    }
+    let options = CompressOptions {
+        quality,
+        ..CompressOptions::default()
+    };
+    validate_options(&options)?;
+
+    let source = prepare_source(bytes, &options)?;
+    let format = match target_format {
+        Some(name) => parse_format(&name)?,
+        None => source.format,
+    };
+
+    widths
+        .iter()
+        .map(|&width| {
+            let resized = map_source(source.clone(), |image| {
+                resize_image_to(image, width, 0, ResizeMode::Fit, options.resampling())
+            });
+            let output = encode_source(&resized, format, &options)?;
+            Ok(build_result(
+                bytes,
+                &resized,
+                format,
+                output,
+                options.quality_for(format),
+            ))
+        })
+        .collect()
+}
+
+/// Compress raw RGBA pixels, like the image data of a canvas, without decoding an image file
+/// - pixels: RGBA bytes of every pixel, row by row (Uint8Array or Uint8ClampedArray from frontend)
+/// - width: Image width
+/// - height: Image height
+/// - target_format: Output format: png, jpeg, webp, gif, qoi, bmp or tiff
+/// - quality: Compression quality (0-100, lower means worse quality)
+#[wasm_bindgen]
+pub fn compress_rgba(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    target_format: &str,
+    quality: u8,
+) -> Result<Vec<u8>, Error> {
+    let format = parse_format(target_format)?;
+    let expected = width as u64 * height as u64 * 4;
+    if pixels.len() as u64 != expected {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            format!(
+                "Expected {expected} bytes for {width}x{height} RGBA pixels, got {}",
+                pixels.len()
+            ),
+        ));
+    }
+    let options = CompressOptions {
+        quality,
+        ..CompressOptions::default()
+    };
+    validate_options(&options)?;
+
+    let image =
+        image::RgbaImage::from_vec(width, height, pixels.to_vec()).expect("Failed to create image");
+    // There is no input file to fall back to, so the source counts as edited
+    let source = Source {
+        format,
+        original_width: width,
+        original_height: height,
+        image: DynamicImage::from(image),
+        frames: Vec::new(),
+        repeat: Repeat::Infinite,
+        metadata: Metadata::default(),
+        edited: true,
+    };
+    Ok(encode_source(&source, format, &options)?.bytes)
+}
+
+/// Decoded RGBA pixels, returned by `decode_rgba`
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Clone, Debug)]
+pub struct DecodedImage {
+    /// RGBA bytes of every pixel, row by row
+    pub pixels: Vec<u8>,
+    /// Image width
+    pub width: u32,
+    /// Image height
+    pub height: u32,
+}
+
+/// Decode and resize an image to raw RGBA pixels, like for drawing a preview on a canvas
+/// - bytes: Image byte array (Uint8Array from frontend)
+/// - resize_percent: Size scaling factor (0-1, smaller means smaller size)
+/// - quantize_quality: Quality to quantize the pixels with, so they look like the PNG output
+///   at that quality (optional, not quantized when not set)
+///
+/// Animations return their first frame.
+#[wasm_bindgen]
+pub fn decode_rgba(
+    bytes: &[u8],
+    resize_percent: f32,
+    quantize_quality: Option<u8>,
+) -> Result<DecodedImage, Error> {
+    let options = CompressOptions {
+        quality: quantize_quality.unwrap_or(100),
+        resize_percent,
+        ..CompressOptions::default()
+    };
+    validate_options(&options)?;
+
+    let source = prepare_source(bytes, &options)?;
+    let image = match quantize_quality {
+        Some(_) => quantify_png_with_rgba(source.image, &options)?.0,
+        None => source.image.into_rgba8(),
+    };
+
+    Ok(DecodedImage {
+        width: image.width(),
+        height: image.height(),
+        pixels: image.into_raw(),
+    })
+}
+
+/// Color of an image with the share of pixels it covers, returned by `dominant_colors`
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug)]
+pub struct DominantColor {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+    /// Fraction of the pixels having this color after quantization (0-1)
+    pub coverage: f64,
+}
+
+/// Find the most common colors of an image, like for placeholder backgrounds or theming
+/// - bytes: Image byte array (Uint8Array from frontend)
+/// - n: Maximum number of colors (1-256)
+///
+/// The image is quantized to `n` colors without dithering, and the palette is returned sorted by
+/// the number of pixels mapped to each color, most common first. Animations use their first
+/// frame.
+#[wasm_bindgen]
+pub fn dominant_colors(bytes: &[u8], n: u16) -> Result<Vec<DominantColor>, Error> {
+    if !(1..=256).contains(&n) {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "n must be between 1 and 256",
+        ));
+    }
+    // The quantizer needs at least 2 colors, a single color is the most common of 2
+    let options = CompressOptions {
+        quality: 100,
+        max_colors: n.max(2),
+        dither_level: 0.0,
+        ..CompressOptions::default()
+    };
+
+    let source = decode_source(bytes, true, DEFAULT_MAX_PIXELS)?;
+    let Quantized {
+        palette, indexes, ..
+    } = quantify_and_get_platte_and_indexes(source.image, &options)?;
+
+    let mut counts = vec![0usize; palette.len()];
+    for index in &indexes {
+        counts[*index as usize] += 1;
+    }
+    let mut colors = palette
+        .iter()
+        .zip(counts)
+        .filter(|(_, count)| *count > 0)
+        .map(|(rgba, count)| DominantColor {
+            r: rgba.r,
+            g: rgba.g,
+            b: rgba.b,
+            a: rgba.a,
+            coverage: count as f64 / indexes.len().max(1) as f64,
+        })
+        .collect::<Vec<_>>();
+    colors.sort_by(|a, b| b.coverage.total_cmp(&a.coverage));
+    colors.truncate(n as usize);
+
+    Ok(colors)
+}
+
+/// Most colors `unique_colors` counts exactly, the size of a PNG palette
+const UNIQUE_COLORS_LIMIT: usize = 256;
+
+/// Count the distinct RGBA colors of an image, like for telling whether it fits a PNG palette
+/// without losing colors
+/// - bytes: Image byte array (Uint8Array from frontend)
+///
+/// Counting stops once there are more than 256 colors and returns 257, so 256 or less means the
+/// image can be stored as an indexed PNG exactly. Animations use their first frame.
+#[wasm_bindgen]
+pub fn unique_colors(bytes: &[u8]) -> Result<u32, Error> {
+    let source = decode_source(bytes, true, DEFAULT_MAX_PIXELS)?;
+    let count = palette::unique_colors(&source.image.into_rgba8(), UNIQUE_COLORS_LIMIT)
+        .map_or(UNIQUE_COLORS_LIMIT + 1, |colors| colors.len());
+    Ok(count as u32)
+}
+
+/// Check whether any pixel of an image is transparent, like for avoiding JPEG output
+/// - bytes: Image byte array (Uint8Array from frontend)
+///
+/// Unlike `probe`, which reports whether the format has an alpha channel, this looks at the
+/// pixels, so a PNG with an alpha channel that is opaque everywhere returns false. Every frame
+/// of an animation is checked, and the check stops at the first transparent pixel.
+#[wasm_bindgen]
+pub fn has_alpha(bytes: &[u8]) -> Result<bool, Error> {
+    let source = decode_source(bytes, true, DEFAULT_MAX_PIXELS)?;
+    Ok(!is_opaque(&source.image)
+        || source
+            .frames
+            .iter()
+            .any(|frame| frame.buffer().pixels().any(|pixel| pixel[3] < 255)))
+}
+
+/// Largest width and height an image is downsampled to before computing its BlurHash
+const BLURHASH_MAX_SIZE: u32 = 32;
+
+/// Compute the BlurHash of an image, a short string that decodes to a blurred placeholder
+/// - bytes: Image byte array (Uint8Array from frontend)
+/// - x_components: Number of horizontal components (1-9, more keeps more detail)
+/// - y_components: Number of vertical components (1-9)
+///
+/// The image is downsampled to at most 32x32 pixels first, which hardly changes the hash.
+/// Transparent pixels are blended onto white. Animations use their first frame.
+#[wasm_bindgen]
+pub fn blurhash(bytes: &[u8], x_components: u32, y_components: u32) -> Result<String, Error> {
+    if !(1..=9).contains(&x_components) || !(1..=9).contains(&y_components) {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "BlurHash components must be between 1 and 9",
+        ));
+    }
+    let source = decode_source(bytes, true, DEFAULT_MAX_PIXELS)?;
+    let image = source
+        .image
+        .resize(BLURHASH_MAX_SIZE, BLURHASH_MAX_SIZE, FilterType::Triangle);
+    let image = flatten_alpha(image, Rgba([255, 255, 255, 255])).into_rgb8();
+
+    Ok(blurhash::encode(&image, x_components, y_components))
+}
+
+/// Map an image onto a fixed palette and encode it as an indexed PNG
+/// - bytes: Image byte array (Uint8Array from frontend)
+/// - colors: Hex colors of the palette, like `#ff0000` or `#00000000` (2-256 colors)
+/// - dither: Whether to dither, spreading the error of every pixel onto its neighbors
+/// - resize_percent: Size scaling factor (0-1, smaller means smaller size)
+///
+/// Every pixel takes the nearest palette color, by Euclidean distance in RGBA, instead of
+/// the colors being chosen by quantization. Animations keep their first frame.
+#[wasm_bindgen]
+pub fn compress_with_palette(
+    bytes: &[u8],
+    colors: Vec<String>,
+    dither: bool,
+    resize_percent: f32,
+) -> Result<Vec<u8>, Error> {
+    if !(2..=256).contains(&colors.len()) {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            format!(
+                "Palette must have between 2 and 256 colors, got {}",
+                colors.len()
+            ),
+        ));
+    }
+    let palette = colors
+        .iter()
+        .map(|color| {
+            let Rgba([r, g, b, a]) = parse_color(color)?;
+            Ok(RGBA { r, g, b, a })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let options = CompressOptions {
+        resize_percent,
+        ..CompressOptions::default()
+    };
+    validate_options(&options)?;
+
+    let source = prepare_source(bytes, &options)?;
+    encode_with_palette(&source, palette, dither, &options)
+}
+
+/// Palette computed once by `build_palette`, to map many images onto the same colors with
+/// `remap_with_palette`
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct SharedPalette {
+    /// Colors, transparent ones first
+    colors: Vec<RGBA>,
+}
+
+#[wasm_bindgen]
+impl SharedPalette {
+    /// Number of colors
+    pub fn color_count(&self) -> usize {
+        self.colors.len()
+    }
+
+    /// Colors as `#rrggbbaa` hex, in palette order, to store the palette or pass it to
+    /// `compress_with_palette`
+    pub fn colors(&self) -> Vec<String> {
+        self.colors
+            .iter()
+            .map(|rgba| format!("#{:02x}{:02x}{:02x}{:02x}", rgba.r, rgba.g, rgba.b, rgba.a))
+            .collect()
+    }
+}
+
+/// Compute one palette for a set of images, like the sprites of a tileset
+/// - images: Image byte arrays (Uint8Array from frontend)
+/// - max_colors: Maximum number of palette colors (2-256)
+/// - quality: Quantization quality (0-100, lower means worse quality)
+///
+/// The colors of all images are collected in one histogram and quantized together, so every
+/// image is covered as well as the shared colors allow. Animations contribute their first frame.
+#[wasm_bindgen]
+pub fn build_palette(
+    images: Vec<js_sys::Uint8Array>,
+    max_colors: u16,
+    quality: u8,
+) -> Result<SharedPalette, Error> {
+    if images.is_empty() {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "At least one image is required",
+        ));
+    }
+    let options = CompressOptions {
+        quality,
+        max_colors,
+        ..CompressOptions::default()
+    };
+    validate_options(&options)?;
+
+    let mut quantizer = imagequant::new();
+    quantizer.set_quality(0, options.quality)?;
+    quantizer.set_max_colors(options.max_colors as u32)?;
+    quantizer.set_speed(options.speed as i32)?;
+
+    let mut histogram = imagequant::Histogram::new(&quantizer);
+    for image in &images {
+        let source = decode_source(&image.to_vec(), true, options.max_pixels)?;
+        let image = source.image.into_rgba8();
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let pixels = image
+            .pixels()
+            .map(|pixel| {
+                let [r, g, b, a] = pixel.0;
+                RGBA { r, g, b, a }
+            })
+            .collect::<Vec<_>>();
+        let mut q_img = QImage::new(&quantizer, pixels, width, height, 0.)?;
+        histogram.add_image(&quantizer, &mut q_img)?;
+    }
+
+    let mut colors = histogram.quantize(&quantizer)?.palette_vec();
+    // Transparent entries first keep the tRNS chunk of every remapped image short
+    colors.sort_by_key(|rgba| rgba.a == 255);
+    Ok(SharedPalette { colors })
+}
+
+/// Map an image onto a palette from `build_palette` and encode it as an indexed PNG
+/// - palette: Shared palette
+/// - bytes: Image byte array (Uint8Array from frontend)
+/// - dither: Whether to dither, spreading the error of every pixel onto its neighbors
+///
+/// Every output has the palette in the same order, so indexes mean the same color across
+/// images. Animations keep their first frame.
+#[wasm_bindgen]
+pub fn remap_with_palette(
+    palette: &SharedPalette,
+    bytes: &[u8],
+    dither: bool,
+) -> Result<Vec<u8>, Error> {
+    let options = CompressOptions::default();
+    let source = prepare_source(bytes, &options)?;
+    encode_with_palette(&source, palette.colors.clone(), dither, &options)
+}
+
+/// Map the image of a source onto a fixed palette and encode it as an indexed PNG
+/// - source: Image to encode, animations keep their first frame
+/// - palette: Colors to map to, 1-256
+/// - dither: Whether to dither
+/// - options: Compression options
+fn encode_with_palette(
+    source: &Source,
+    palette: Vec<RGBA>,
+    dither: bool,
+    options: &CompressOptions,
+) -> Result<Vec<u8>, Error> {
+    let image = source.image.to_rgba8();
+    let quantized = Quantized {
+        indexes: palette::remap_to_palette(&image, &palette, dither),
+        palette,
+        quality: None,
+    };
+
+    let mut output = Vec::new();
+    let (encoder, data) = indexed_png_encoder(
+        &mut output,
+        image.width(),
+        image.height(),
+        options,
+        &source.metadata,
+        quantized,
+        false,
+    )?;
+    encoder.write_header()?.write_image_data(&data)?;
+    Ok(output)
+}
+
+/// One frame of an animation as a PNG, returned by `explode_frames`
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Clone, Debug)]
+pub struct ExplodedFrame {
+    /// Frame encoded as a lossless PNG
+    pub bytes: Vec<u8>,
+    /// How long the frame is shown, in milliseconds
+    pub delay_ms: u32,
+    /// Position of the frame in the animation, from 0
+    pub index: u32,
+}
+
+/// Split an animated GIF, PNG or WebP into one PNG per frame, like for editing the frames
+/// - bytes: Image byte array (Uint8Array from frontend)
+///
+/// Every frame is the full canvas as shown at that point of the animation, encoded losslessly.
+/// A still image gives a single frame with no delay.
+#[wasm_bindgen]
+pub fn explode_frames(bytes: &[u8]) -> Result<Vec<ExplodedFrame>, Error> {
+    let source = decode_source(bytes, true, DEFAULT_MAX_PIXELS)?;
+    let frames = if source.frames.is_empty() {
+        vec![(source.image, 0)]
+    } else {
+        source
+            .frames
+            .into_iter()
+            .map(|frame| {
+                let (numer, denom) = frame.delay().numer_denom_ms();
+                let delay_ms = (numer as f64 / denom as f64).round() as u32;
+                (DynamicImage::from(frame.into_buffer()), delay_ms)
+            })
+            .collect()
+    };
+
+    frames
+        .into_iter()
+        .enumerate()
+        .map(|(index, (image, delay_ms))| {
+            let mut output = Vec::new();
+            image.write_with_encoder(PngEncoder::new_with_quality(
+                &mut output,
+                CompressionType::Best,
+                image::codecs::png::FilterType::Adaptive,
+            ))?;
+            Ok(ExplodedFrame {
+                bytes: output,
+                delay_ms,
+                index: index as u32,
+            })
+        })
+        .collect()
+}
+
+/// Build an animated GIF from frame images, like sprite frames or the output of `explode_frames`
+/// - frames: Image byte arrays of the frames, all of the same size (Array of Uint8Array from
+///   frontend)
+/// - delays: How long every frame is shown, in milliseconds
+/// - repeat: Number of times the animation repeats after playing once (optional, 0 or not set
+///   loops forever)
+///
+/// The frames are quantized and written like GIF output of `compress`. Animated frame images
+/// use their first frame.
+#[wasm_bindgen]
+pub fn assemble_gif(
+    frames: Vec<js_sys::Uint8Array>,
+    delays: Vec<u16>,
+    repeat: Option<u16>,
+) -> Result<Vec<u8>, Error> {
+    if frames.is_empty() {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "At least one frame is required",
+        ));
+    }
+    if frames.len() != delays.len() {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            format!(
+                "Expected one delay per frame, got {} frames and {} delays",
+                frames.len(),
+                delays.len()
+            ),
+        ));
+    }
+    let options = CompressOptions {
+        repeat,
+        ..CompressOptions::default()
+    };
+
+    let frames = frames
+        .iter()
+        .zip(&delays)
+        .map(|(bytes, &delay)| {
+            let image = decode_source(&bytes.to_vec(), true, DEFAULT_MAX_PIXELS)?.image;
+            let delay = Delay::from_numer_denom_ms(delay as u32, 1);
+            Ok(Frame::from_parts(image.into_rgba8(), 0, 0, delay))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let (width, height) = frames[0].buffer().dimensions();
+    if let Some(frame) = frames
+        .iter()
+        .find(|frame| frame.buffer().dimensions() != (width, height))
+    {
+        let (frame_width, frame_height) = frame.buffer().dimensions();
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            format!(
+                "Frames must all be {width}x{height}, got a {frame_width}x{frame_height} frame"
+            ),
+        ));
+    }
+
+    let source = Source {
+        format: ImageFormat::Gif,
+        original_width: width,
+        original_height: height,
+        image: DynamicImage::from(frames[0].buffer().clone()),
+        frames,
+        repeat: Repeat::Infinite,
+        metadata: Metadata::default(),
+        edited: true,
+    };
+    Ok(encode_source(&source, ImageFormat::Gif, &options)?.bytes)
+}
+
+/// Create a multi-page TIFF, like for archiving scanned documents, with one page per image
+/// - images: Image byte arrays of the pages (Array of Uint8Array from frontend)
+/// - compression: TIFF compression (optional, defaults to Deflate)
+///
+/// Pages are stored losslessly at their own size. Animated images use their first frame.
+#[wasm_bindgen]
+pub fn make_tiff(
+    images: Vec<js_sys::Uint8Array>,
+    compression: Option<TiffCompression>,
+) -> Result<Vec<u8>, Error> {
+    if images.is_empty() {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "At least one image is required",
+        ));
+    }
+    let pages = images
+        .iter()
+        .map(|bytes| Ok(decode_source(&bytes.to_vec(), true, DEFAULT_MAX_PIXELS)?.image))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let mut output = Vec::new();
+    encode_tiff(
+        &pages,
+        compression.unwrap_or_default(),
+        Cursor::new(&mut output),
+    )?;
+    Ok(output)
+}
+
+/// Largest width and height of an ICO image
+const ICO_MAX_SIZE: u32 = 256;
+
+/// Create an ICO file, like a favicon, holding the image at several square sizes
+/// - bytes: Image byte array (Uint8Array from frontend)
+/// - sizes: Width and height of every embedded image, like 16, 32 and 48 (at most 256)
+///
+/// Non-square images are fitted inside the square, the rest stays transparent.
+#[wasm_bindgen]
+pub fn make_ico(bytes: &[u8], sizes: Vec<u32>) -> Result<Vec<u8>, Error> {
+    if sizes.is_empty() {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "At least one size is required",
+        ));
+    }
+    if let Some(size) = sizes
+        .iter()
+        .find(|size| !(1..=ICO_MAX_SIZE).contains(*size))
+    {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            format!("ICO sizes must be between 1 and {ICO_MAX_SIZE}, got {size}"),
+        ));
+    }
+    let source = decode_source(bytes, true, DEFAULT_MAX_PIXELS)?;
+
+    let images = sizes
+        .iter()
+        .map(|&size| {
+            let image = source
+                .image
+                .resize(size, size, ResizeFilter::default().into())
+                .into_rgba8();
+            let mut square = image::RgbaImage::new(size, size);
+            let left = (size - image.width()) / 2;
+            let top = (size - image.height()) / 2;
+            image::imageops::replace(&mut square, &image, left as i64, top as i64);
+            square
+        })
+        .collect::<Vec<_>>();
+    let frames = images
+        .iter()
+        .map(|image| {
+            IcoFrame::as_png(
+                image.as_raw(),
+                image.width(),
+                image.height(),
+                ExtendedColorType::Rgba8,
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut output = Vec::new();
+    IcoEncoder::new(&mut output).encode_images(&frames)?;
+    Ok(output)
+}
+
+/// JPEG rotated without re-encoding, returned by `rotate_jpeg_lossless`
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Clone, Debug)]
+pub struct LosslessRotation {
+    /// Rotated JPEG, or the input as is when it couldn't be rotated losslessly
+    pub bytes: Vec<u8>,
+    /// Whether the image was rotated
+    pub rotated: bool,
+    /// Why the input was returned as is
+    pub warning: Option<String>,
+}
+
+/// Rotate a JPEG image without re-encoding it, so no quality is lost
+/// - bytes: JPEG byte array (Uint8Array from frontend)
+/// - degrees: Clockwise rotation, a multiple of 90
+///
+/// The DCT coefficients are moved like jpegtran does, which is also much faster than decoding
+/// and encoding. Only sequential JPEG whose flipped sides are a multiple of its 8 or 16 pixel
+/// blocks can be rotated this way: progressive JPEG and other sizes return the input as is with
+/// a warning, so it can be rotated with `transform` and re-encoded instead. Metadata is copied,
+/// with the EXIF orientation reset so viewers don't rotate the image again.
+#[wasm_bindgen]
+pub fn rotate_jpeg_lossless(bytes: &[u8], degrees: i32) -> Result<LosslessRotation, Error> {
+    let transform = match degrees.rem_euclid(360) {
+        0 => Transform::None,
+        90 => Transform::Rotate90,
+        180 => Transform::Rotate180,
+        270 => Transform::Rotate270,
+        _ => {
+            return Err(Error::new(
+                ErrorCode::InvalidArgument,
+                format!("degrees must be a multiple of 90, got {degrees}"),
+            ));
+        }
+    };
+    if image::guess_format(bytes)? != ImageFormat::Jpeg {
+        return Err(Error::new(
+            ErrorCode::UnsupportedFormat,
+            "rotate_jpeg_lossless expects a JPEG image",
+        ));
+    }
+
+    match jpeg_transform::transform(bytes, transform, DEFAULT_MAX_PIXELS) {
+        Ok(rotated) => Ok(LosslessRotation {
+            bytes: rotated,
+            rotated: transform != Transform::None,
+            warning: None,
+        }),
+        Err(error) if error.kind() == ErrorCode::UnsupportedFormat => Ok(LosslessRotation {
+            bytes: bytes.to_vec(),
+            rotated: false,
+            warning: Some(error.message().to_string()),
+        }),
+        Err(error) => Err(error),
+    }
+}
+
+/// Basic information about an image, read by `probe`
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Clone, Debug)]
+pub struct ImageInfo {
+    /// Width of the image, after applying its EXIF orientation
+    pub width: u32,
+    /// Height of the image, after applying its EXIF orientation
+    pub height: u32,
+    /// Image format: png, jpeg, webp, gif, qoi, bmp or tiff
+    pub format: String,
+    /// Whether the image has an alpha channel
+    pub has_alpha: bool,
+    /// Whether the image is a CMYK or YCCK JPEG, whose colors are converted to RGB when compressed
+    pub is_cmyk: bool,
+    /// Whether the image has more than one frame
+    pub is_animated: bool,
+    /// Number of frames, 1 for still images
+    pub frame_count: u32,
+    /// Number of times the animation plays, 0 for forever (not set for still images)
+    pub plays: Option<u32>,
+}
+
+/// Read dimensions, format and animation of an image without decoding its pixels
+/// - bytes: Image byte array (Uint8Array from frontend)
+///
+/// Only the headers are read, except for GIF where the frames are decoded to count them.
+#[wasm_bindgen]
+pub fn probe(bytes: &[u8]) -> Result<ImageInfo, Error> {
+    let format = image::guess_format(bytes)?;
+    let mut decoder = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    // Output images are rotated by their orientation, so report the rotated size
+    let (width, height) = match decoder.orientation()? {
+        Orientation::Rotate90
+        | Orientation::Rotate270
+        | Orientation::Rotate90FlipH
+        | Orientation::Rotate270FlipH => (height, width),
+        _ => (width, height),
+    };
+    let has_alpha = decoder.color_type().has_alpha();
+
+    let frame_count = match format {
+        ImageFormat::Gif => {
+            let decoder = GifDecoder::new(Cursor::new(bytes))?;
+            decoder.into_frames().count() as u32
+        }
+        ImageFormat::Png => {
+            let reader = png::Decoder::new(Cursor::new(bytes)).read_info()?;
+            reader
+                .info()
+                .animation_control
+                .map_or(1, |animation| animation.num_frames)
+        }
+        ImageFormat::WebP => {
+            let decoder = image_webp::WebPDecoder::new(Cursor::new(bytes))?;
+            if decoder.is_animated() {
+                decoder.num_frames()
+            } else {
+                1
+            }
+        }
+        _ => 1,
+    };
+    let plays = if frame_count > 1 {
+        Some(repeat_plays(read_repeat(format, bytes)?))
+    } else {
+        None
+    };
+
+    Ok(ImageInfo {
+        width,
+        height,
+        format: format_name(format).to_string(),
+        has_alpha,
+        is_cmyk: format == ImageFormat::Jpeg && metadata::is_cmyk_jpeg(bytes),
+        is_animated: frame_count > 1,
+        frame_count,
+        plays,
+    })
+}
+
+/// Detect the format of an image from its signature, like for validating uploads
+/// - bytes: Image byte array (Uint8Array from frontend)
+///
+/// Only the first bytes are read. Returns the format name as in `probe`, like `png` or `jpeg`,
+/// `svg` for SVG documents, or the usual file extension for formats that can be recognized but
+/// not compressed, like `avif`.
+#[wasm_bindgen]
+pub fn guess_format(bytes: &[u8]) -> Result<String, Error> {
+    if svg::is_svg(bytes) {
+        return Ok("svg".to_string());
+    }
+    let format = image::guess_format(bytes)?;
+    let name = match format_name(format) {
+        "unknown" => format
+            .extensions_str()
+            .first()
+            .copied()
+            .unwrap_or("unknown"),
+        name => name,
+    };
+    Ok(name.to_string())
+}
+
+/// Check that a resize factor is above 0 and at most 1
+///
+/// Upscaling by accident, like passing 10 for 10%, would make a huge image, so factors above 1
+/// fail. `width` and `height` enlarge on purpose.
+fn validate_resize_percent(resize_percent: f32) -> Result<(), Error> {
+    if resize_percent > 0.0 && resize_percent <= 1.0 {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorCode::InvalidArgument,
+            format!("resize_percent must be above 0 and at most 1, got {resize_percent}"),
+        ))
+    }
+}
+
+/// Check that options are in their valid ranges
+fn validate_options(options: &CompressOptions) -> Result<(), Error> {
+    validate_resize_percent(options.resize_percent)?;
+    if !(2..=256).contains(&options.max_colors) {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "max_colors must be between 2 and 256",
+        ));
+    }
+    if !(0.0..=1.0).contains(&options.dither_level) {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "dither_level must be between 0 and 1",
+        ));
+    }
+    if options.min_quality > 100 {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "min_quality must be between 0 and 100",
+        ));
+    }
+    for (name, quality) in [
+        ("quality", Some(options.quality)),
+        ("jpeg_quality", options.jpeg_quality),
+        ("webp_quality", options.webp_quality),
+        ("png_quality", options.png_quality),
+    ] {
+        if let Some(quality) = quality.filter(|&quality| quality > 100) {
+            return Err(Error::new(
+                ErrorCode::InvalidArgument,
+                format!("{name} must be between 0 and 100, got {quality}"),
+            ));
+        }
+    }
+    if !(1..=10).contains(&options.speed) {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "speed must be between 1 and 10",
+        ));
+    }
+    if let Some(background) = &options.background {
+        parse_color(background)?;
+    }
+    if let Some(pad_color) = &options.pad_color {
+        parse_color(pad_color)?;
+    }
+    if !(-255..=255).contains(&options.brightness) {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "brightness must be between -255 and 255",
+        ));
+    }
+    if !(options.contrast >= 0.0 && options.contrast.is_finite()) {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "contrast must be at least 0",
+        ));
+    }
+    if !(options.blur >= 0.0 && options.blur.is_finite()) {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "blur must be at least 0",
+        ));
+    }
+    if (options.blur_width == 0) != (options.blur_height == 0) {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "blur_width and blur_height must both be set to blur a rectangle",
+        ));
+    }
+    if options.posterize.is_some_and(|levels| levels < 2) {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "posterize must be between 2 and 255",
+        ));
+    }
+    if options.near_lossless > 100 {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "near_lossless must be between 0 and 100",
+        ));
+    }
+    if options.dpi == Some(0) {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "dpi must be at least 1",
+        ));
+    }
+    for (keyword, _) in &options.png_text {
+        if !(1..=79).contains(&keyword.chars().count()) || !is_latin1(keyword) {
+            return Err(Error::new(
+                ErrorCode::InvalidArgument,
+                format!("PNG text keyword must be 1-79 Latin-1 characters, got {keyword:?}"),
+            ));
+        }
+    }
+    if (options.crop_width == 0) != (options.crop_height == 0) {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "crop_width and crop_height must both be set to crop",
+        ));
+    }
+    if (options.aspect_width == 0) != (options.aspect_height == 0) {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "aspect_width and aspect_height must both be set to crop",
+        ));
+    }
+    Ok(())
+}
+
+/// Maximum number of encodes in one quality search of `compress_to_size`
+const SIZE_SEARCH_MAX_ITERATIONS: u32 = 8;
+/// Maximum number of times `compress_to_size` shrinks the image further
+const SIZE_SEARCH_MAX_RESIZES: u32 = 3;
+
+/// Compress image to fit in a byte budget, using the highest quality that fits
+/// - bytes: Image byte array (Uint8Array from frontend)
+/// - max_bytes: Maximum size of the output in bytes
+/// - resize_percent: Size scaling factor (0-1, smaller means smaller size)
+///
+/// The quality is binary searched with at most 8 encodes. If even quality 0 is too large, the
+/// image is shrunk further and searched again, up to 3 times. When nothing fits, the smallest
+/// output found is returned, so compare `compressed_size` with `max_bytes`.
+#[wasm_bindgen]
+pub fn compress_to_size(
+    bytes: &[u8],
+    max_bytes: usize,
+    resize_percent: f32,
+) -> Result<CompressionResult, Error> {
+    validate_resize_percent(resize_percent)?;
+    let decoded = decode_source(bytes, true, DEFAULT_MAX_PIXELS)?;
+
+    let mut resize_percent = resize_percent;
+    let mut smallest: Option<CompressionResult> = None;
+
+    for _ in 0..=SIZE_SEARCH_MAX_RESIZES {
+        let source = resize_source(
+            decoded.clone(),
+            resize_percent,
+            CompressOptions::default().resampling(),
+        );
+
+        let (mut low, mut high) = (0, 100);
+        let mut best = None;
+        for _ in 0..SIZE_SEARCH_MAX_ITERATIONS {
+            if low > high {
+                break;
+            }
+            let quality = (low + high) / 2;
+            let options = CompressOptions {
+                quality,
+                ..CompressOptions::default()
+            };
+            let output = encode_source(&source, source.format, &options)?;
+            if output.bytes.len() <= max_bytes {
+                best = Some((output, quality));
+                low = quality + 1;
+            } else {
+                if smallest
+                    .as_ref()
+                    .is_none_or(|result| output.bytes.len() < result.compressed_size)
+                {
+                    smallest = Some(build_result(bytes, &source, source.format, output, quality));
+                }
+                if quality == 0 {
+                    break;
+                }
+                high = quality - 1;
+            }
+        }
+
+        if let Some((output, quality)) = best {
+            return Ok(build_result(bytes, &source, source.format, output, quality));
+        }
+
+        // Even the lowest quality is too large, shrink the area by the remaining size ratio
+        let smallest_size = smallest
+            .as_ref()
+            .map_or(max_bytes, |result| result.compressed_size);
+        resize_percent *= (max_bytes as f32 / smallest_size as f32).sqrt();
+    }
+
+    let mut result =
+        smallest.ok_or_else(|| Error::new(ErrorCode::EncodeFailed, "Failed to compress image"))?;
+    result.warnings.push(format!(
+        "No quality fits in {max_bytes} bytes, so the smallest output was returned"
+    ));
+    Ok(result)
+}
+
+/// Maximum number of encodes in the quality search of `compress_to_quality`
+const SSIM_SEARCH_MAX_ITERATIONS: u32 = 8;
+
+/// Compress image with the lowest quality that still reaches a structural similarity
+/// - bytes: Image byte array (Uint8Array from frontend)
+/// - min_ssim: Minimum SSIM of the output (0-1, 1 is identical)
+/// - resize_percent: Size scaling factor (0-1, smaller means smaller size)
+///
+/// The quality is binary searched with at most 8 encodes, each measured like `measure_quality`.
+/// The result carries the chosen `quality` and its `ssim`. When no quality reaches `min_ssim`,
+/// the most similar output found is returned, so compare `ssim` with `min_ssim`.
+#[wasm_bindgen]
+pub fn compress_to_quality(
+    bytes: &[u8],
+    min_ssim: f64,
+    resize_percent: f32,
+) -> Result<CompressionResult, Error> {
+    if !(0.0..=1.0).contains(&min_ssim) {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "min_ssim must be between 0 and 1",
+        ));
+    }
+    validate_resize_percent(resize_percent)?;
+    let source = decode_source(bytes, true, DEFAULT_MAX_PIXELS)?;
+    let source = resize_source(
+        source,
+        resize_percent,
+        CompressOptions::default().resampling(),
+    );
+
+    let (mut low, mut high) = (0, 100);
+    let mut best: Option<CompressionResult> = None;
+    let mut most_similar: Option<CompressionResult> = None;
+    for _ in 0..SSIM_SEARCH_MAX_ITERATIONS {
+        if low > high {
+            break;
+        }
+        let quality = (low + high) / 2;
+        let options = CompressOptions {
+            quality,
+            ..CompressOptions::default()
+        };
+        let output = encode_source(&source, source.format, &options)?;
+        let mut result = build_result(bytes, &source, source.format, output, quality);
+        measure_quality(&source, &mut result, &options)?;
+
+        if result.ssim.is_some_and(|ssim| ssim >= min_ssim) {
+            best = Some(result);
+            if quality == 0 {
+                break;
+            }
+            high = quality - 1;
+        } else {
+            if most_similar
+                .as_ref()
+                .is_none_or(|similar| result.ssim > similar.ssim)
+            {
+                most_similar = Some(result);
+            }
+            low = quality + 1;
+        }
+    }
+
+    if let Some(best) = best {
+        return Ok(best);
+    }
+    let mut result = most_similar
+        .ok_or_else(|| Error::new(ErrorCode::EncodeFailed, "Failed to compress image"))?;
+    result.warnings.push(format!(
+        "No quality reaches an SSIM of {min_ssim}, so the most similar output was returned"
+    ));
+    Ok(result)
+}
+
+/// Largest unsharp mask radius of `CompressOptions.sharpen`
+const SHARPEN_MAX_SIGMA: f32 = 5.0;
+/// Smallest difference to the blurred image that is sharpened, so flat areas and noise stay as is
+const SHARPEN_THRESHOLD: i32 = 2;
+/// Share of the pixels at each end of the histogram that `auto_levels` lets clip, so a few
+/// outliers don't hold back the stretch
+const AUTO_LEVELS_CLIP: f64 = 0.005;
+
+/// Decode the input and apply the resizing and color options before encoding
+fn prepare_source(bytes: &[u8], options: &CompressOptions) -> Result<Source, Error> {
+    let mut source = if svg::is_svg(bytes) {
+        svg_source(
+            bytes,
+            options.svg_width,
+            options.svg_height,
+            options.max_pixels,
+        )?
+    } else {
+        decode_source(bytes, options.strip_metadata, options.max_pixels)?
+    };
+    if options.gif_still_as_png && source.format == ImageFormat::Gif && source.frames.len() <= 1 {
+        source = Source {
+            format: ImageFormat::Png,
+            frames: Vec::new(),
+            edited: true,
+            ..source
+        };
+    }
+    if options.frame_skip > 1 && source.frames.len() > 1 {
+        source = skip_frames(source, options.frame_skip as usize);
+    }
+    // The decoded image is already upright, so the transform is relative to how it's displayed
+    if options.transform != Transform::None {
+        source = map_source(source, |image| options.transform.apply(image));
+    }
+    // Crop first, so the resize works on the pixels that are kept
+    if options.crop_width > 0 && options.crop_height > 0 {
+        source = crop_source(source, options)?;
+    }
+    if options.aspect_width > 0 && options.aspect_height > 0 {
+        source = map_source(source, |image| {
+            crop_to_aspect(&image, options.aspect_width, options.aspect_height)
+        });
+    }
+    let mut source = if options.width > 0 || options.height > 0 {
+        map_source(source, |image| {
+            resize_image_to(
+                image,
+                options.width,
+                options.height,
+                options.resize_mode,
+                options.resampling(),
+            )
+        })
+    } else if options.max_dimension > 0 {
+        let longest = source.image.width().max(source.image.height());
+        if longest > options.max_dimension {
+            // Fitting into a square sizes the longer side exactly, without percent rounding
+            map_source(source, |image| {
+                resize_image_to(
+                    image,
+                    options.max_dimension,
+                    options.max_dimension,
+                    ResizeMode::Fit,
+                    options.resampling(),
+                )
+            })
+        } else {
+            source
+        }
+    } else {
+        resize_source(source, options.resize_percent, options.resampling())
+    };
+    if options.blur > 0.0 {
+        source = map_source(source, |image| blur_region(image, options));
+    }
+    if options.auto_levels {
+        // Animations take the levels of the first frame, so the frames don't flicker
+        if let Some((black, white)) = levels_range(&source.image) {
+            source = map_source(source, |image| stretch_levels(image, black, white));
+        }
+    }
+    if options.brightness != 0 {
+        source = map_source(source, |image| image.brighten(options.brightness));
+    }
+    if options.contrast != 1.0 {
+        // `image` takes the change in percent
+        let percent = (options.contrast - 1.0) * 100.0;
+        source = map_source(source, |image| image.adjust_contrast(percent));
+    }
+    if options.sharpen > 0.0 {
+        let sigma = options.sharpen.min(SHARPEN_MAX_SIGMA);
+        source = map_source(source, |image| image.unsharpen(sigma, SHARPEN_THRESHOLD));
+    }
+    if options.grayscale {
+        source = map_source(source, |image| image.grayscale());
+    }
+    if let Some(levels) = options.posterize {
+        source = map_source(source, |image| posterize(image, levels));
+    }
+    if options.pad_width > 0 || options.pad_height > 0 {
+        source = pad_source(source, options)?;
+    }
+    match options.mask {
+        Mask::None => {}
+        Mask::Circle => {
+            source = map_source(source, |image| {
+                let square = crop_to_aspect(&image, 1, 1);
+                let radius = square.width() as f32 / 2.0;
+                round_corners(square, radius)
+            });
+        }
+        Mask::RoundedRect => {
+            source = map_source(source, |image| {
+                round_corners(image, options.corner_radius as f32)
+            });
+        }
+    }
+    if let Some(threshold) = options.alpha_threshold {
+        source = map_source(source, |image| threshold_alpha(image, threshold));
+    }
+    if options.drop_alpha {
+        let background = options.background_color()?;
+        source = map_source(source, |image| flatten_alpha(image, background));
+    }
+    if options.dpi.is_some() {
+        source.metadata.dpi = options.dpi;
+    }
+    // A new loop count can't be kept by falling back to the input
+    if repeat_plays(output_repeat(&source, options)) != repeat_plays(source.repeat) {
+        source.edited = true;
+    }
+    // Nor can added text, when a PNG input could be returned in place of PNG output
+    if !options.png_text.is_empty() && source.format == ImageFormat::Png {
+        source.edited = true;
+    }
+    // Forced output is never replaced by the input
+    if options.force {
+        source.edited = true;
+    }
+    Ok(source)
+}
+
+/// Decoded input image
+#[derive(Clone)]
+struct Source {
+    /// Input format
+    format: ImageFormat,
+    /// Dimensions of the input before resizing
+    original_width: u32,
+    original_height: u32,
+    /// Image to encode (first frame for GIF)
+    image: DynamicImage,
+    /// All frames of a GIF or an animated PNG or WebP, empty for other formats
+    frames: Vec<Frame>,
+    /// Loop count of the animation
+    repeat: Repeat,
+    /// Metadata to write into the output
+    metadata: Metadata,
+    /// Whether the pixels or loop count were changed after decoding, so the input can't replace
+    /// the output
+    edited: bool,
+}
+
+/// Default of `CompressOptions.max_pixels`, 100 megapixels
+const DEFAULT_MAX_PIXELS: u32 = 100_000_000;
+
+/// Decode image, including every frame of a GIF
+/// - bytes: Image byte array
+/// - strip_metadata: Whether to skip reading the metadata to carry into the output
+/// - max_pixels: Largest width times height to decode, checked on the header before allocating
+///   and on the frames of an animation together, as each is decoded at the size of the canvas
+///   (0 for no limit)
+fn decode_source(bytes: &[u8], strip_metadata: bool, max_pixels: u32) -> Result<Source, Error> {
+    // SVG has no signature `image` knows and is rendered instead, at its own size
+    if svg::is_svg(bytes) {
+        return svg_source(bytes, 0, 0, max_pixels);
+    }
+    // Get image format
+    let format = image::guess_format(bytes)?;
+    // Load image and apply its EXIF orientation. Kept EXIF gets its orientation reset, so
+    // viewers won't rotate the output a second time.
+    let mut decoder = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    if max_pixels > 0 && width as u64 * height as u64 > max_pixels as u64 {
+        return Err(Error::new(
+            ErrorCode::ImageTooLarge,
+            format!("Image of {width}x{height} pixels exceeds max_pixels {max_pixels}"),
+        ));
+    }
+    let orientation = decoder.orientation()?;
+    let metadata = if strip_metadata {
+        // The resolution says nothing private, so it is kept even when stripping metadata
+        Metadata {
+            dpi: metadata::read_dpi(&mut decoder, format, bytes)?,
+            ..Metadata::default()
+        }
+    } else {
+        metadata::read_metadata(&mut decoder, format, bytes)?
+    };
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    // Frames come out composited onto the full canvas, after the disposal method of the
+    // previous frame cleared its rectangle or restored the canvas from before it
+    let frames = match format {
+        ImageFormat::Gif => {
+            let decoder = GifDecoder::new(Cursor::new(bytes))?;
+            collect_frames(decoder.into_frames(), max_pixels)?
+        }
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(Cursor::new(bytes))?;
+            if decoder.is_apng()? {
+                collect_frames(decoder.apng()?.into_frames(), max_pixels)?
+            } else {
+                Vec::new()
+            }
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(Cursor::new(bytes))?;
+            if decoder.has_animation() {
+                collect_frames(decoder.into_frames(), max_pixels)?
+            } else {
+                Vec::new()
+            }
+        }
+        _ => Vec::new(),
+    };
+    let repeat = read_repeat(format, bytes)?;
+
+    Ok(Source {
+        format,
+        original_width: image.width(),
+        original_height: image.height(),
+        image,
+        frames,
+        repeat,
+        metadata,
+        edited: false,
+    })
+}
+
+/// Decode the frames of an animation, stopping once they hold more than `max_pixels` together
+/// - frames: Frames of the decoder, each covering the whole canvas
+/// - max_pixels: Largest width times height of all frames together, 0 for no limit
+///
+/// A small file can hold thousands of tiny frames on a large canvas, which would each be
+/// decoded as a full canvas, so the header check of the canvas alone doesn't bound the memory.
+fn collect_frames(frames: image::Frames, max_pixels: u32) -> Result<Vec<Frame>, Error> {
+    let mut collected = Vec::new();
+    let mut pixels = 0u64;
+    for frame in frames {
+        let frame = frame?;
+        let (width, height) = frame.buffer().dimensions();
+        pixels += width as u64 * height as u64;
+        if max_pixels > 0 && pixels > max_pixels as u64 {
+            return Err(Error::new(
+                ErrorCode::ImageTooLarge,
+                format!(
+                    "Animation frames exceed max_pixels {max_pixels} together, after {} frames \
+                     of {width}x{height} pixels",
+                    collected.len() + 1
+                ),
+            ));
+        }
+        collected.push(frame);
+    }
+    Ok(collected)
+}
+
+/// Read the loop count of an animation, `Repeat::Infinite` for still images
+fn read_repeat(format: ImageFormat, bytes: &[u8]) -> Result<Repeat, Error> {
+    // APNG and WebP count plays with 0 for forever, GIF counts repeats after the first play
+    let from_plays = |plays: u32| match plays {
+        0 => Repeat::Infinite,
+        plays => Repeat::Finite((plays - 1).min(u16::MAX as u32) as u16),
+    };
+    Ok(match format {
+        ImageFormat::Gif => metadata::gif_repeat(bytes),
+        ImageFormat::Png => {
+            let reader = png::Decoder::new(Cursor::new(bytes)).read_info()?;
+            reader
+                .info()
+                .animation_control
+                .map_or(Repeat::Infinite, |animation| {
+                    from_plays(animation.num_plays)
+                })
+        }
+        ImageFormat::WebP => match image_webp::WebPDecoder::new(Cursor::new(bytes))?.loop_count() {
+            image_webp::LoopCount::Forever => Repeat::Infinite,
+            image_webp::LoopCount::Times(plays) => from_plays(plays.get() as u32),
+        },
+        _ => Repeat::Infinite,
+    })
+}
+
+/// Loop count of the output animation, the input's unless overridden by the options
+fn output_repeat(source: &Source, options: &CompressOptions) -> Repeat {
+    match options.repeat {
+        None => source.repeat,
+        Some(0) => Repeat::Infinite,
+        Some(count) => Repeat::Finite(count),
+    }
+}
+
+/// Number of plays of a loop count for APNG and WebP, 0 for forever
+fn repeat_plays(repeat: Repeat) -> u32 {
+    match repeat {
+        Repeat::Infinite => 0,
+        Repeat::Finite(count) => count as u32 + 1,
+    }
+}
+
+/// Resize image and every frame
+fn resize_source(source: Source, resize_percent: f32, resampling: Resampling) -> Source {
+    if resize_percent == 1.0 {
+        return source;
+    }
+    let image = resize_image(source.image, resize_percent, resampling);
+    let frames = source
+        .frames
+        .into_iter()
+        .map(|frame| {
+            // Keep the frame delay and offsets, scaled along with the frame
+            let delay = frame.delay();
+            let left = (frame.left() as f32 * resize_percent) as u32;
+            let top = (frame.top() as f32 * resize_percent) as u32;
+            let image = DynamicImage::from(frame.into_buffer());
+            let image = resize_image(image, resize_percent, resampling).into_rgba8();
+            Frame::from_parts(image, left, top, delay)
+        })
+        .collect();
+
+    Source {
+        image,
+        frames,
+        edited: true,
+        ..source
+    }
+}
+
+/// Render an SVG document as the source of a PNG
+/// - bytes: SVG document
+/// - width: Width to render at (0 for auto)
+/// - height: Height to render at (0 for auto)
+/// - max_pixels: Largest width times height to render, 0 for no limit
+///
+/// There is no input image to fall back to, so the source counts as edited.
+fn svg_source(bytes: &[u8], width: u32, height: u32, max_pixels: u32) -> Result<Source, Error> {
+    let image = svg::rasterize(bytes, width, height, max_pixels)?;
+    Ok(Source {
+        format: ImageFormat::Png,
+        original_width: image.width(),
+        original_height: image.height(),
+        image: DynamicImage::from(image),
+        frames: Vec::new(),
+        repeat: Repeat::Infinite,
+        metadata: Metadata::default(),
+        edited: true,
+    })
+}
+
+/// Apply an image operation to the image and every frame
+fn map_source(source: Source, operation: impl Fn(DynamicImage) -> DynamicImage) -> Source {
+    let image = operation(source.image);
+    let frames = source
+        .frames
+        .into_iter()
+        .map(|frame| {
+            let (left, top, delay) = (frame.left(), frame.top(), frame.delay());
+            let image = operation(DynamicImage::from(frame.into_buffer())).into_rgba8();
+            Frame::from_parts(image, left, top, delay)
+        })
+        .collect();
+
+    Source {
+        image,
+        frames,
+        edited: true,
+        ..source
+    }
+}
+
+/// Blend a transparent image onto an opaque background color
+fn flatten_alpha(image: DynamicImage, background: Rgba<u8>) -> DynamicImage {
+    let image = image.into_rgba8();
+    let (width, height) = image.dimensions();
+    let Rgba([background_r, background_g, background_b, _]) = background;
+
+    let buf = image
+        .pixels()
+        .flat_map(|pixel| {
+            let [r, g, b, a] = pixel.0;
+            let blend = |color: u8, background: u8| {
+                let (color, background, a) = (color as u32, background as u32, a as u32);
+                ((color * a + background * (255 - a) + 127) / 255) as u8
+            };
+            [
+                blend(r, background_r),
+                blend(g, background_g),
+                blend(b, background_b),
+            ]
+        })
+        .collect();
+
+    DynamicImage::from(RgbImage::from_vec(width, height, buf).expect("Failed to create image"))
+}
+
+/// Find the black and white points of an image for `auto_levels`
+/// - image: Image to measure
+///
+/// Returns the darkest and brightest luma once the clipped share of pixels at both ends is left
+/// out, ignoring fully transparent pixels, or `None` when they are equal.
+fn levels_range(image: &DynamicImage) -> Option<(u8, u8)> {
+    let mut histogram = [0usize; 256];
+    for pixel in image.to_luma_alpha8().pixels() {
+        if pixel.0[1] > 0 {
+            histogram[pixel.0[0] as usize] += 1;
+        }
+    }
+    let clip = (histogram.iter().sum::<usize>() as f64 * AUTO_LEVELS_CLIP) as usize;
+    // First luma from each end at which more than the clipped number of pixels are counted
+    let mut count = 0;
+    let black = (0..256).find(|&level| {
+        count += histogram[level];
+        count > clip
+    })?;
+    let mut count = 0;
+    let white = (0..256).rev().find(|&level| {
+        count += histogram[level];
+        count > clip
+    })?;
+    (black < white).then_some((black as u8, white as u8))
+}
+
+/// Stretch every color channel linearly so `black` becomes 0 and `white` 255
+/// - image: Image to process
+/// - black: Value mapped to 0, lower values are clipped
+/// - white: Value mapped to 255, higher values are clipped, above `black`
+///
+/// All channels take the same mapping to keep the hues. Alpha is kept as is. Images with more
+/// than 8 bits per channel are converted to 8 bits.
+fn stretch_levels(image: DynamicImage, black: u8, white: u8) -> DynamicImage {
+    let (black, range) = (black as f32, (white - black) as f32);
+    let table: [u8; 256] = std::array::from_fn(|value| {
+        ((value as f32 - black) * 255.0 / range)
+            .round()
+            .clamp(0.0, 255.0) as u8
+    });
+    let stretch = |value: &mut u8| *value = table[*value as usize];
+    match image {
+        DynamicImage::ImageLuma8(mut image) => {
+            image.iter_mut().for_each(stretch);
+            DynamicImage::from(image)
+        }
+        DynamicImage::ImageRgb8(mut image) => {
+            image.iter_mut().for_each(stretch);
+            DynamicImage::from(image)
+        }
+        DynamicImage::ImageLumaA8(mut image) => {
+            image.pixels_mut().for_each(|pixel| stretch(&mut pixel[0]));
+            DynamicImage::from(image)
+        }
+        image => {
+            let mut image = image.into_rgba8();
+            image
+                .pixels_mut()
+                .for_each(|pixel| pixel.0[..3].iter_mut().for_each(stretch));
+            DynamicImage::from(image)
+        }
+    }
+}
+
+/// Reduce every color channel to evenly spaced levels, like 0, 85, 170 and 255 for 4 levels
+/// - image: Image to process
+/// - levels: Number of levels per channel, at least 2
+///
+/// Alpha is kept as is. Images with more than 8 bits per channel are converted to 8 bits.
+fn posterize(image: DynamicImage, levels: u8) -> DynamicImage {
+    let step = 255.0 / (levels - 1) as f32;
+    let quantize = |value: &mut u8| *value = ((*value as f32 / step).round() * step).round() as u8;
+    match image {
+        DynamicImage::ImageLuma8(mut image) => {
+            image.iter_mut().for_each(quantize);
+            DynamicImage::from(image)
+        }
+        DynamicImage::ImageRgb8(mut image) => {
+            image.iter_mut().for_each(quantize);
+            DynamicImage::from(image)
+        }
+        DynamicImage::ImageLumaA8(mut image) => {
+            image.pixels_mut().for_each(|pixel| quantize(&mut pixel[0]));
+            DynamicImage::from(image)
+        }
+        image => {
+            let mut image = image.into_rgba8();
+            image
+                .pixels_mut()
+                .for_each(|pixel| pixel.0[..3].iter_mut().for_each(quantize));
+            DynamicImage::from(image)
+        }
+    }
+}
+
+/// Make alpha either fully transparent or fully opaque
+/// - image: Image to process
+/// - threshold: Alpha below this becomes 0, the rest 255
+///
+/// Images without alpha are kept as is. Images with more than 8 bits per channel are converted
+/// to 8 bits.
+fn threshold_alpha(image: DynamicImage, threshold: u8) -> DynamicImage {
+    let binarize = |alpha: &mut u8| *alpha = if *alpha < threshold { 0 } else { 255 };
+    match image {
+        image if !image.color().has_alpha() => image,
+        DynamicImage::ImageLumaA8(mut image) => {
+            image.pixels_mut().for_each(|pixel| binarize(&mut pixel[1]));
+            DynamicImage::from(image)
+        }
+        image => {
+            let mut image = image.into_rgba8();
+            image.pixels_mut().for_each(|pixel| binarize(&mut pixel[3]));
+            DynamicImage::from(image)
+        }
+    }
+}
+
+/// Parse a hex color: `#rgb`, `#rgba`, `#rrggbb` or `#rrggbbaa`, the `#` being optional
+fn parse_color(hex: &str) -> Result<Rgba<u8>, Error> {
+    let digits = hex.strip_prefix('#').unwrap_or(hex);
+    let invalid = || Error::new(ErrorCode::InvalidArgument, format!("Invalid color: {hex}"));
+    if !digits.is_ascii() {
+        return Err(invalid());
+    }
+
+    let channels = match digits.len() {
+        // Short form, every digit is doubled
+        3 | 4 => digits
+            .chars()
+            .map(|digit| digit.to_digit(16).map(|value| value as u8 * 17))
+            .collect::<Option<Vec<_>>>(),
+        6 | 8 => (0..digits.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&digits[index..index + 2], 16).ok())
+            .collect::<Option<Vec<_>>>(),
+        _ => None,
+    }
+    .ok_or_else(invalid)?;
+
+    Ok(Rgba([
+        channels[0],
+        channels[1],
+        channels[2],
+        channels.get(3).copied().unwrap_or(255),
+    ]))
+}
+
+/// Whether the image has 16-bit integer channels
+fn is_16_bit(image: &DynamicImage) -> bool {
+    matches!(
+        image.color(),
+        image::ColorType::L16
+            | image::ColorType::La16
+            | image::ColorType::Rgb16
+            | image::ColorType::Rgba16
+    )
+}
+
+/// Whether the image has more than 8 bits per channel
+fn has_high_bit_depth(image: &DynamicImage) -> bool {
+    let color = image.color();
+    color.bytes_per_pixel() > color.channel_count()
+}
+
+/// Whether every pixel of the image is fully opaque
+fn is_opaque(image: &DynamicImage) -> bool {
+    if !image.color().has_alpha() {
+        return true;
+    }
+    image.to_rgba8().pixels().all(|pixel| pixel[3] == 255)
+}
+
+/// Whether every pixel of the image is gray
+fn is_grayscale(image: &DynamicImage) -> bool {
+    if !image.color().has_color() {
+        return true;
+    }
+    image.to_rgb8().pixels().all(|pixel| {
+        let [r, g, b] = pixel.0;
+        r == g && g == b
+    })
+}
+
+/// Encode image
+/// - source: Image to encode
+/// - format: Output format
+/// - options: Compression options
+fn encode_source(
+    source: &Source,
+    format: ImageFormat,
+    options: &CompressOptions,
+) -> Result<Encoded, Error> {
+    // The encoders read `quality`, so it is replaced by the quality of the format
+    let options = &CompressOptions {
+        quality: options.quality_for(format),
+        ..options.clone()
+    };
+    let image = source.image.clone();
+    // Quality 100 keeps 16 bits per channel in PNG output
+    let lossless_16_bit = format == ImageFormat::Png
+        && options.quality == 100
+        && source.frames.len() <= 1
+        && is_16_bit(&image);
+    let bit_depth_reduced = has_high_bit_depth(&image) && !lossless_16_bit;
+
+    // Final encoded image data
+    let mut output = Vec::new();
+    // Size and colors of every GIF frame
+    let mut gif_frames = Vec::new();
+
+    let quantization_quality = match format {
+        ImageFormat::Png if lossless_16_bit => {
+            encode_png_16_bit(image, options, &source.metadata, &mut output)?;
+            None
+        }
+        ImageFormat::Png if source.frames.len() > 1 => {
+            let repeat = output_repeat(source, options);
+            quantify_apng_with_color_index(
+                &source.frames,
+                repeat,
+                options,
+                &source.metadata,
+                &mut output,
+            )?
+        }
+        ImageFormat::Png if options.drop_alpha => {
+            // Some decoders load indexed PNGs as palette images, so the quantized colors are
+            // written as RGB samples, or gray ones for a gray image
+            let (image, quality) = quantify_png_with_rgba(image, options)?;
+            encode_png_rgb(image.into(), options, &source.metadata, &mut output)?;
+            quality
+        }
+        ImageFormat::Png => {
+            // Quantify PNG image
+            quantify_png_with_color_index(image, options, &source.metadata, &mut output)?
+        }
+        ImageFormat::Jpeg => {
+            encode_jpeg(image, options, &source.metadata, &mut output)?;
+            None
+        }
+        ImageFormat::WebP if source.frames.len() > 1 => {
+            let repeat = output_repeat(source, options);
+            encode_animated_webp(
+                &source.frames,
+                repeat,
+                options,
+                &source.metadata,
+                &mut output,
+            )?
+        }
+        ImageFormat::WebP => encode_webp(image, options, &source.metadata, &mut output)?,
+        ImageFormat::Gif => {
+            // A still image becomes a single frame
+            let still;
+            let frames = if source.frames.is_empty() {
+                still = [Frame::new(image.into_rgba8())];
+                &still[..]
+            } else {
+                &source.frames[..]
+            };
+            let (width, height) = frames[0].buffer().dimensions();
+            let delta = if options.gif_delta_frames && frames.len() > 1 {
+                delta_frames(frames)
+            } else {
+                None
+            };
+            let gif = GifCanvas {
+                width,
+                height,
+                repeat: (frames.len() > 1).then(|| output_repeat(source, options)),
+                keep_frames: delta.is_some(),
+            };
+            let frames = delta.as_deref().unwrap_or(frames);
+            if options.gif_global_palette {
+                let (quality, stats) =
+                    quantify_gif_with_global_palette(frames, &gif, options, &mut output)?;
+                gif_frames = stats;
+                quality
+            } else {
+                // The worst frame is reported as the quality of the whole animation
+                let quantized = quantize_frames(frames, options)?;
+                let quality = quantized
+                    .iter()
+                    .filter_map(|(_, frame_quality)| *frame_quality)
+                    .min();
+                let frames = quantized.into_iter().map(|(frame, _)| frame);
+
+                gif_frames = write_gif_frames(frames, &gif, &mut output)?;
+                quality
+            }
+        }
+        ImageFormat::Qoi => {
+            // Lossless, the quality doesn't apply
+            let image = if image.color().has_alpha() {
+                DynamicImage::from(image.into_rgba8())
+            } else {
+                DynamicImage::from(image.into_rgb8())
+            };
+            image.write_with_encoder(QoiEncoder::new(&mut output))?;
+            None
+        }
+        ImageFormat::Bmp => {
+            // Uncompressed, the quality doesn't apply. An alpha channel costs a byte per pixel,
+            // so it is only kept when some pixel is transparent.
+            let image = match image.color() {
+                image::ColorType::L8 | image::ColorType::Rgb8 => image,
+                _ if !is_opaque(&image) => DynamicImage::from(image.into_rgba8()),
+                _ if is_grayscale(&image) => DynamicImage::from(image.into_luma8()),
+                _ => DynamicImage::from(image.into_rgb8()),
+            };
+            image.write_with_encoder(BmpEncoder::new(&mut output))?;
+            None
+        }
+        ImageFormat::Tiff => {
+            // Lossless, the quality doesn't apply. Every frame of an animation becomes a page.
+            let pages = if source.frames.is_empty() {
+                vec![image]
+            } else {
+                source
+                    .frames
+                    .iter()
+                    .map(|frame| DynamicImage::from(frame.buffer().clone()))
+                    .collect()
+            };
+            encode_tiff(&pages, options.tiff_compression, Cursor::new(&mut output))?;
+            None
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorCode::UnsupportedFormat,
+                "Unsupported image format",
+            ));
+        }
+    };
+
+    // Other formats write the first frame, or every frame as a TIFF page
+    let is_animated = source.frames.len() > 1
+        && matches!(
+            format,
+            ImageFormat::Gif | ImageFormat::Png | ImageFormat::WebP
+        );
+    let repeat = is_animated.then(|| output_repeat(source, options));
+
+    Ok(Encoded {
+        bytes: output,
+        quantization_quality,
+        bit_depth_reduced,
+        gif_frames,
+        repeat,
+    })
+}
+
+/// Quantize every frame of a GIF on its own, returning the frames with their achieved quality
+/// - frames: Frames to quantize
+/// - options: Compression options
+///
+/// GIF has a single fully transparent color, so pixels that are more than half transparent in
+/// the input become fully transparent, with a palette entry kept free for them, and all other
+/// pixels become opaque.
+///
+/// With the `parallel` feature, frames are quantized in batches of one frame per thread and the
+/// progress is reported after every batch, otherwise after every frame.
+fn quantize_frames(
+    frames: &[Frame],
+    options: &CompressOptions,
+) -> Result<Vec<(Frame, Option<u8>)>, Error> {
+    let settings = options.quantization();
+    let quantize_frame = |frame: &Frame| {
+        let (width, height) = frame.buffer().dimensions();
+        let is_transparent = |pixel: &Rgba<u8>| pixel.0[3] < GIF_OPAQUE_ALPHA;
+        let settings = if frame.buffer().pixels().any(is_transparent) {
+            Quantization {
+                max_colors: settings.max_colors.min(255),
+                ..settings
+            }
+        } else {
+            settings
+        };
+        let quantized = quantize(frame.buffer().clone(), settings)?;
+        let quality = quantized.quality;
+        let mut image = palette_image(quantized, width, height);
+        for (pixel, input) in image.pixels_mut().zip(frame.buffer().pixels()) {
+            *pixel = if is_transparent(input) || is_transparent(pixel) {
+                Rgba([0, 0, 0, 0])
+            } else {
+                Rgba([pixel.0[0], pixel.0[1], pixel.0[2], 255])
+            };
+        }
+        Ok::<_, Error>((
+            Frame::from_parts(image, frame.left(), frame.top(), frame.delay()),
+            quality,
+        ))
+    };
+
+    #[cfg(feature = "parallel")]
+    let batch_size = rayon::current_num_threads();
+    #[cfg(not(feature = "parallel"))]
+    let batch_size = 1;
+
+    let mut quantized = Vec::with_capacity(frames.len());
+    for batch in frames.chunks(batch_size) {
+        #[cfg(feature = "parallel")]
+        let results = {
+            use rayon::prelude::*;
+            batch.par_iter().map(quantize_frame).collect::<Vec<_>>()
+        };
+        #[cfg(not(feature = "parallel"))]
+        let results = batch.iter().map(quantize_frame).collect::<Vec<_>>();
+
+        for result in results {
+            quantized.push(result?);
+        }
+        report_progress(options, quantized.len(), frames.len())?;
+    }
+
+    Ok(quantized)
+}
+
+/// Call the progress callback of the options, if any
+/// - options: Compression options
+/// - done: Number of finished steps
+/// - total: Number of steps
+fn report_progress(options: &CompressOptions, done: usize, total: usize) -> Result<(), Error> {
+    if let Some(on_progress) = &options.on_progress {
+        on_progress
+            .call2(
+                &JsValue::NULL,
+                &JsValue::from(done as u32),
+                &JsValue::from(total as u32),
+            )
+            .map_err(|_| Error::new(ErrorCode::CallbackFailed, "Progress callback failed"))?;
+    }
+    Ok(())
+}
+
+/// Encoded image data
+struct Encoded {
+    bytes: Vec<u8>,
+    /// Quality the quantization achieved, when the image was quantized
+    quantization_quality: Option<u8>,
+    /// Whether the input had more than 8 bits per channel and the output has 8
+    bit_depth_reduced: bool,
+    /// Size and colors of every frame, for GIF output
+    gif_frames: Vec<GifFrameStats>,
+    /// Loop count written, for animations
+    repeat: Option<Repeat>,
+}
+
+/// Build the result for an encoded image, falling back to the input when the output is larger,
+/// in the same format and the pixels weren't edited
+/// - bytes: Input image data
+/// - source: Image that was encoded
+/// - format: Output format
+/// - output: Encoded image
+/// - quality: Quality used to encode
+fn build_result(
+    bytes: &[u8],
+    source: &Source,
+    format: ImageFormat,
+    output: Encoded,
+    quality: u8,
+) -> CompressionResult {
+    if format == source.format && !source.edited && output.bytes.len() > bytes.len() {
+        let mut result = original_result(bytes, source, quality);
+        result.warnings.push(format!(
+            "The output of {} bytes was larger than the input, so the input was returned",
+            output.bytes.len()
+        ));
+        return result;
+    }
+
+    let warnings = output_warnings(source, format, &output);
+    CompressionResult {
+        original_size: bytes.len(),
+        compressed_size: output.bytes.len(),
+        bytes: output.bytes,
+        width: source.image.width(),
+        height: source.image.height(),
+        format: format_name(format).to_string(),
+        quality,
+        quantization_quality: output.quantization_quality,
+        bit_depth_reduced: output.bit_depth_reduced,
+        psnr: None,
+        ssim: None,
+        returned_original: false,
+        frame_sizes: output.gif_frames.iter().map(|frame| frame.size).collect(),
+        frame_colors: output.gif_frames.iter().map(|frame| frame.colors).collect(),
+        plays: output.repeat.map(repeat_plays),
+        warnings,
+    }
+}
+
+/// Describe what the output format lost of the source
+/// - source: Image that was encoded
+/// - format: Output format
+/// - output: Encoded image
+fn output_warnings(source: &Source, format: ImageFormat, output: &Encoded) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let frame_count = source.frames.len();
+    // TIFF writes every frame as a page
+    if frame_count > 1 && output.repeat.is_none() && format != ImageFormat::Tiff {
+        warnings.push(format!(
+            "Only the first of {frame_count} frames was kept, as {} can't be animated",
+            format_name(format).to_uppercase()
+        ));
+    }
+    if format == ImageFormat::Jpeg && !is_opaque(&source.image) {
+        warnings.push(
+            "Transparency was blended onto the background, as JPEG has no alpha channel"
+                .to_string(),
+        );
+    }
+    if format == ImageFormat::Gif && source.image.color().has_alpha() {
+        let is_partial = |pixel: &Rgba<u8>| pixel.0[3] > 0 && pixel.0[3] < 255;
+        let partial = if source.frames.is_empty() {
+            source.image.to_rgba8().pixels().any(is_partial)
+        } else {
+            let mut pixels = source
+                .frames
+                .iter()
+                .flat_map(|frame| frame.buffer().pixels());
+            pixels.any(is_partial)
+        };
+        if partial {
+            warnings.push(
+                "Partly transparent pixels were made fully transparent or opaque, as GIF has a \
+                 single transparent color"
+                    .to_string(),
+            );
+        }
+    }
+    if output.bit_depth_reduced {
+        warnings.push("The input was reduced to 8 bits per channel".to_string());
+    }
+    warnings
+}
+
+/// Build the result returning the input as is
+/// - bytes: Input image data
+/// - source: Decoded input
+/// - quality: Quality requested for the output
+fn original_result(bytes: &[u8], source: &Source, quality: u8) -> CompressionResult {
+    CompressionResult {
+        bytes: bytes.to_vec(),
+        original_size: bytes.len(),
+        compressed_size: bytes.len(),
+        width: source.original_width,
+        height: source.original_height,
+        format: format_name(source.format).to_string(),
+        quality,
+        quantization_quality: None,
+        bit_depth_reduced: false,
+        psnr: None,
+        ssim: None,
+        returned_original: true,
+        frame_sizes: Vec::new(),
+        frame_colors: Vec::new(),
+        plays: (source.frames.len() > 1).then(|| repeat_plays(source.repeat)),
+        warnings: Vec::new(),
+    }
+}
+
+/// Whether the input can be returned without encoding, by `skip_below_bytes` or
+/// `skip_optimized`
+/// - bytes: Input image data
+/// - source: Decoded input
+/// - options: Compression options
+///
+/// Edited pixels always need encoding. Otherwise, JPEG re-encoded at a higher quality than it
+/// was saved with or an indexed PNG that quantization would keep exactly rarely gets smaller, so
+/// the encode would most likely end with the input being returned anyway.
+fn is_already_optimized(bytes: &[u8], source: &Source, options: &CompressOptions) -> bool {
+    if source.edited {
+        return false;
+    }
+    if bytes.len() <= options.skip_below_bytes {
+        return true;
+    }
+    if !options.skip_optimized {
+        return false;
+    }
+    match source.format {
+        ImageFormat::Jpeg => metadata::jpeg_quality(bytes)
+            .is_some_and(|quality| quality <= options.quality_for(ImageFormat::Jpeg)),
+        ImageFormat::Png => {
+            source.frames.len() <= 1
+                && metadata::png_palette_size(bytes)
+                    .is_some_and(|colors| colors <= options.max_colors as usize)
+        }
+        _ => false,
+    }
+}
+
+/// Parse an output format name
+fn parse_format(name: &str) -> Result<ImageFormat, Error> {
+    match name {
+        "png" => Ok(ImageFormat::Png),
+        "jpeg" | "jpg" => Ok(ImageFormat::Jpeg),
+        "webp" => Ok(ImageFormat::WebP),
+        "gif" => Ok(ImageFormat::Gif),
+        "qoi" => Ok(ImageFormat::Qoi),
+        "bmp" => Ok(ImageFormat::Bmp),
+        "tiff" | "tif" => Ok(ImageFormat::Tiff),
+        _ => Err(Error::new(
+            ErrorCode::UnsupportedFormat,
+            format!("Unsupported target format: {name}"),
+        )),
+    }
+}
+
+/// Name of an image format as reported to the frontend
+fn format_name(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Png => "png",
+        ImageFormat::Jpeg => "jpeg",
+        ImageFormat::WebP => "webp",
+        ImageFormat::Gif => "gif",
+        ImageFormat::Qoi => "qoi",
+        ImageFormat::Bmp => "bmp",
+        ImageFormat::Tiff => "tiff",
+        _ => "unknown",
+    }
+}
+
+fn resize_image(image: DynamicImage, resize_percent: f32, resampling: Resampling) -> DynamicImage {
+    if resize_percent == 1.0 {
+        return image;
+    }
+    let (width, height) = (image.width(), image.height());
+    let new_width = (width as f32 * resize_percent) as u32;
+    let new_height = (height as f32 * resize_percent) as u32;
+    resize_in_light(image, resampling.linear, |image| {
+        if resampling.is_area(&image, new_width, new_height) {
+            return area_resize(&image, new_width, new_height);
+        }
+        resampling.halve(image, new_width, new_height).resize(
+            new_width,
+            new_height,
+            resampling.filter.into(),
+        )
+    })
+}
+
+/// Largest output side that is resized by area averaging whatever the filter, as the ringing
+/// and aliasing of the other filters show most on such tiny thumbnails
+const AREA_THUMBNAIL_MAX_SIZE: u32 = 64;
+
+/// Resize settings of the compression options
+#[derive(Clone, Copy, Debug)]
+struct Resampling {
+    filter: ResizeFilter,
+    /// Whether to resize in linear light
+    linear: bool,
+    /// Whether to halve the image before large reductions
+    stepped: bool,
+}
+
+impl Resampling {
+    /// Halve the image for as long as it stays at least as large as the target size, when
+    /// `stepped` is set
+    /// - image: Image to shrink
+    /// - width: Width the image is resized to afterwards
+    /// - height: Height the image is resized to afterwards
+    ///
+    /// The final resize then shrinks by less than half, so its filter only spans a few pixels
+    /// instead of many, which is where the time of large reductions goes.
+    fn halve(self, mut image: DynamicImage, width: u32, height: u32) -> DynamicImage {
+        if !self.stepped {
+            return image;
+        }
+        while image.width() / 2 >= width.max(1) && image.height() / 2 >= height.max(1) {
+            image = halve_image(image);
+        }
+        image
+    }
+
+    /// Whether to resize by area averaging: with the `Area` filter, or with any filter but
+    /// `Nearest` when shrinking to a thumbnail of at most 64x64 pixels
+    /// - image: Image to resize
+    /// - width: Width the image is resized to
+    /// - height: Height the image is resized to
+    fn is_area(self, image: &DynamicImage, width: u32, height: u32) -> bool {
+        self.filter == ResizeFilter::Area
+            || (self.filter != ResizeFilter::Nearest
+                && width.max(height) <= AREA_THUMBNAIL_MAX_SIZE
+                && width < image.width()
+                && height < image.height())
+    }
+}
+
+/// Resize an image to exactly the given size by area averaging: every output pixel is the
+/// average of the input pixels it covers, weighted by how much of each it covers
+///
+/// This is a box filter as wide as the reduction, which can't alias and is cheap, as every
+/// input pixel is read once per row and column it falls in. Enlarging repeats pixels like
+/// `Nearest`, with the edges between them blended.
+fn area_resize(image: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let (width, height) = (width.max(1), height.max(1));
+    let columns = area_spans(image.width(), width);
+    let rows = area_spans(image.height(), height);
+    let (columns, rows) = (&columns[..], &rows[..]);
+    match image {
+        DynamicImage::ImageLuma8(buffer) => {
+            area_average(buffer, columns, rows, f32::from, |value| {
+                value.round() as u8
+            })
+            .into()
+        }
+        DynamicImage::ImageLumaA8(buffer) => {
+            area_average(buffer, columns, rows, f32::from, |value| {
+                value.round() as u8
+            })
+            .into()
+        }
+        DynamicImage::ImageRgb8(buffer) => {
+            area_average(buffer, columns, rows, f32::from, |value| {
+                value.round() as u8
+            })
+            .into()
+        }
+        DynamicImage::ImageRgba8(buffer) => {
+            area_average(buffer, columns, rows, f32::from, |value| {
+                value.round() as u8
+            })
+            .into()
+        }
+        DynamicImage::ImageLuma16(buffer) => {
+            area_average(buffer, columns, rows, f32::from, |value| {
+                value.round() as u16
+            })
+            .into()
+        }
+        DynamicImage::ImageLumaA16(buffer) => {
+            area_average(buffer, columns, rows, f32::from, |value| {
+                value.round() as u16
+            })
+            .into()
+        }
+        DynamicImage::ImageRgb16(buffer) => {
+            area_average(buffer, columns, rows, f32::from, |value| {
+                value.round() as u16
+            })
+            .into()
+        }
+        DynamicImage::ImageRgba16(buffer) => {
+            area_average(buffer, columns, rows, f32::from, |value| {
+                value.round() as u16
+            })
+            .into()
+        }
+        DynamicImage::ImageRgb32F(buffer) => {
+            area_average(buffer, columns, rows, |value| value, |value| value).into()
+        }
+        DynamicImage::ImageRgba32F(buffer) => {
+            area_average(buffer, columns, rows, |value| value, |value| value).into()
+        }
+        _ => image.resize_exact(width, height, FilterType::Triangle),
+    }
+}
+
+/// Input pixels covered by an output pixel along one axis
+struct AreaSpan {
+    /// First input pixel covered
+    start: usize,
+    /// Share of the output pixel every covered input pixel makes up, adding up to 1
+    weights: Vec<f32>,
+}
+
+/// Input pixels covered by every output pixel along one axis
+/// - source: Input size along the axis
+/// - target: Output size along the axis
+fn area_spans(source: u32, target: u32) -> Vec<AreaSpan> {
+    let scale = source as f64 / target as f64;
+    (0..target)
+        .map(|index| {
+            let (left, right) = (index as f64 * scale, (index + 1) as f64 * scale);
+            let start = left.floor() as usize;
+            let end = (right.ceil() as usize).min(source as usize);
+            let weights = (start..end)
+                .map(|pixel| {
+                    let covered = right.min(pixel as f64 + 1.0) - left.max(pixel as f64);
+                    (covered / scale) as f32
+                })
+                .collect();
+            AreaSpan { start, weights }
+        })
+        .collect()
+}
+
+/// Area average an image buffer, first along the rows and then along the columns
+/// - buffer: Image to resize
+/// - columns: Input columns covered by every output column
+/// - rows: Input rows covered by every output row
+/// - to_f32: Convert a sample to float
+/// - from_f32: Convert a float back to a sample, rounding and clamping to its range
+fn area_average<P: image::Pixel>(
+    buffer: &image::ImageBuffer<P, Vec<P::Subpixel>>,
+    columns: &[AreaSpan],
+    rows: &[AreaSpan],
+    to_f32: fn(P::Subpixel) -> f32,
+    from_f32: fn(f32) -> P::Subpixel,
+) -> image::ImageBuffer<P, Vec<P::Subpixel>> {
+    let channels = P::CHANNEL_COUNT as usize;
+    let samples = buffer.as_raw();
+    let (width, height) = (columns.len(), rows.len());
+    let stride = buffer.width() as usize * channels;
+
+    // Every input row averaged down to the output width
+    let mut narrow = vec![0f32; buffer.height() as usize * width * channels];
+    for (row, narrow_row) in samples
+        .chunks_exact(stride)
+        .zip(narrow.chunks_exact_mut(width * channels))
+    {
+        for (column, output) in columns.iter().zip(narrow_row.chunks_exact_mut(channels)) {
+            let covered = &row[column.start * channels..];
+            for (pixel, weight) in covered.chunks_exact(channels).zip(&column.weights) {
+                for (sum, &sample) in output.iter_mut().zip(pixel) {
+                    *sum += to_f32(sample) * weight;
+                }
+            }
+        }
+    }
+
+    let mut output = Vec::with_capacity(width * height * channels);
+    let mut sums = vec![0f32; width * channels];
+    for row in rows {
+        sums.fill(0.0);
+        let covered = narrow[row.start * width * channels..].chunks_exact(width * channels);
+        for (narrow_row, weight) in covered.zip(&row.weights) {
+            for (sum, value) in sums.iter_mut().zip(narrow_row) {
+                *sum += value * weight;
+            }
+        }
+        output.extend(sums.iter().map(|&sum| from_f32(sum)));
+    }
+    image::ImageBuffer::from_raw(width as u32, height as u32, output)
+        .expect("Failed to create image")
+}
+
+/// Halve the width and height of an image, every pixel being the average of a 2x2 block
+///
+/// An odd last row or column is dropped. 8-bit images are averaged directly, others go through
+/// a triangle resize.
+fn halve_image(image: DynamicImage) -> DynamicImage {
+    let (width, height) = (image.width() / 2, image.height() / 2);
+    let halve = |samples: &[u8], channels: usize| {
+        let stride = image.width() as usize * channels;
+        let mut half = Vec::with_capacity(width as usize * height as usize * channels);
+        for y in 0..height as usize {
+            let (top, bottom) = (&samples[2 * y * stride..], &samples[(2 * y + 1) * stride..]);
+            for x in 0..width as usize {
+                for channel in 0..channels {
+                    let index = 2 * x * channels + channel;
+                    let sum = top[index] as u16
+                        + top[index + channels] as u16
+                        + bottom[index] as u16
+                        + bottom[index + channels] as u16;
+                    half.push(((sum + 2) / 4) as u8);
+                }
+            }
+        }
+        half
+    };
+    match &image {
+        DynamicImage::ImageLuma8(buffer) => DynamicImage::from(
+            image::GrayImage::from_raw(width, height, halve(buffer, 1))
+                .expect("Failed to create image"),
+        ),
+        DynamicImage::ImageLumaA8(buffer) => DynamicImage::from(
+            image::GrayAlphaImage::from_raw(width, height, halve(buffer, 2))
+                .expect("Failed to create image"),
+        ),
+        DynamicImage::ImageRgb8(buffer) => DynamicImage::from(
+            RgbImage::from_raw(width, height, halve(buffer, 3)).expect("Failed to create image"),
+        ),
+        DynamicImage::ImageRgba8(buffer) => DynamicImage::from(
+            image::RgbaImage::from_raw(width, height, halve(buffer, 4))
+                .expect("Failed to create image"),
+        ),
+        _ => image.resize_exact(width, height, FilterType::Triangle),
+    }
+}
+
+/// Apply a resize in linear light when `linear` is set, otherwise on the sRGB values as is
+/// - image: Image to process
+/// - linear: Whether to convert to linear light around the resize
+/// - resize: Resize to apply
+///
+/// Averaging sRGB values darkens bright detail on a dark background, as sRGB isn't
+/// proportional to light. The linear image is resized as 32-bit float and converted back to the
+/// color type of the input.
+fn resize_in_light(
+    image: DynamicImage,
+    linear: bool,
+    resize: impl FnOnce(DynamicImage) -> DynamicImage,
+) -> DynamicImage {
+    if !linear {
+        return resize(image);
+    }
+    let color = image.color();
+    let mut float = image.into_rgba32f();
+    for pixel in float.pixels_mut() {
+        for channel in &mut pixel.0[..3] {
+            *channel = srgb_to_linear(*channel);
+        }
+    }
+    let mut resized = resize(DynamicImage::from(float)).into_rgba32f();
+    for pixel in resized.pixels_mut() {
+        for channel in &mut pixel.0[..3] {
+            *channel = linear_to_srgb(*channel);
+        }
+    }
+
+    let resized = DynamicImage::from(resized);
+    match color {
+        image::ColorType::L8 => DynamicImage::from(resized.into_luma8()),
+        image::ColorType::La8 => DynamicImage::from(resized.into_luma_alpha8()),
+        image::ColorType::Rgb8 => DynamicImage::from(resized.into_rgb8()),
+        image::ColorType::L16 => DynamicImage::from(resized.into_luma16()),
+        image::ColorType::La16 => DynamicImage::from(resized.into_luma_alpha16()),
+        image::ColorType::Rgb16 => DynamicImage::from(resized.into_rgb16()),
+        image::ColorType::Rgba16 => DynamicImage::from(resized.into_rgba16()),
+        image::ColorType::Rgb32F => DynamicImage::from(resized.into_rgb32f()),
+        image::ColorType::Rgba32F => resized,
+        _ => DynamicImage::from(resized.into_rgba8()),
+    }
+}
+
+/// Convert an sRGB value (0-1) to linear light
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Convert a linear light value (0-1) back to sRGB
+fn linear_to_srgb(value: f32) -> f32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Keep every n-th frame, adding the delays of the dropped frames so the duration is unchanged
+fn skip_frames(source: Source, skip: usize) -> Source {
+    let mut frames = source.frames.into_iter();
+    let mut kept = Vec::new();
+    // The first frame of every group of `skip` frames is kept, so at least one frame remains
+    while let Some(frame) = frames.next() {
+        let mut duration = Duration::from(frame.delay());
+        for _ in 1..skip {
+            match frames.next() {
+                Some(dropped) => duration += Duration::from(dropped.delay()),
+                None => break,
+            }
+        }
+        let (left, top) = (frame.left(), frame.top());
+        kept.push(Frame::from_parts(
+            frame.into_buffer(),
+            left,
+            top,
+            Delay::from_saturating_duration(duration),
+        ));
+    }
+
+    Source {
+        frames: kept,
+        edited: true,
+        ..source
+    }
+}
+
+/// Crop the image and frames to the crop rectangle of the options, clamped to the image
+fn crop_source(source: Source, options: &CompressOptions) -> Result<Source, Error> {
+    let (width, height) = (source.image.width(), source.image.height());
+    if options.crop_x >= width || options.crop_y >= height {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            "Crop rectangle is outside of the image",
+        ));
+    }
+    let crop_width = options.crop_width.min(width - options.crop_x);
+    let crop_height = options.crop_height.min(height - options.crop_y);
+
+    Ok(map_source(source, |image| {
+        image.crop_imm(options.crop_x, options.crop_y, crop_width, crop_height)
+    }))
+}
+
+/// Blur the image with the blur sigma of the options, only inside the blur rectangle when set
+///
+/// The rectangle is blurred on its own, so no color from outside bleeds in. A rectangle outside of
+/// the image leaves it as is.
+fn blur_region(mut image: DynamicImage, options: &CompressOptions) -> DynamicImage {
+    if options.blur_width == 0 || options.blur_height == 0 {
+        return image.blur(options.blur);
+    }
+    let (width, height) = (image.width(), image.height());
+    if options.blur_x >= width || options.blur_y >= height {
+        return image;
+    }
+    let blur_width = options.blur_width.min(width - options.blur_x);
+    let blur_height = options.blur_height.min(height - options.blur_y);
+
+    let blurred = image
+        .crop_imm(options.blur_x, options.blur_y, blur_width, blur_height)
+        .blur(options.blur);
+    image::imageops::replace(
+        &mut image,
+        &blurred,
+        options.blur_x as i64,
+        options.blur_y as i64,
+    );
+    image
+}
+
+/// Center the image and frames on a canvas of the padding size of the options
+fn pad_source(source: Source, options: &CompressOptions) -> Result<Source, Error> {
+    let (width, height) = (source.image.width(), source.image.height());
+    let pad_width = if options.pad_width > 0 {
+        options.pad_width
+    } else {
+        width
+    };
+    let pad_height = if options.pad_height > 0 {
+        options.pad_height
+    } else {
+        height
+    };
+    if (width > pad_width || height > pad_height) && options.pad_overflow == PadOverflow::Fail {
+        return Err(Error::new(
+            ErrorCode::InvalidArgument,
+            format!(
+                "Image of {width}x{height} is larger than the padded size {pad_width}x{pad_height}"
+            ),
+        ));
+    }
+    let color = match &options.pad_color {
+        Some(color) => parse_color(color)?,
+        None => Rgba([0, 0, 0, 0]),
+    };
+
+    Ok(map_source(source, |image| {
+        pad_image(image, pad_width, pad_height, color)
+    }))
+}
+
+/// Center an image on a canvas filled with a color, cropping what doesn't fit around the center
+/// - image: Image to process
+/// - width: Canvas width
+/// - height: Canvas height
+/// - color: Color of the canvas, which transparent pixels of the image are blended onto
+fn pad_image(image: DynamicImage, width: u32, height: u32, color: Rgba<u8>) -> DynamicImage {
+    let (crop_width, crop_height) = (image.width().min(width), image.height().min(height));
+    let image = image
+        .crop_imm(
+            (image.width() - crop_width) / 2,
+            (image.height() - crop_height) / 2,
+            crop_width,
+            crop_height,
+        )
+        .into_rgba8();
+    let mut canvas = image::RgbaImage::from_pixel(width, height, color);
+    image::imageops::overlay(
+        &mut canvas,
+        &image,
+        ((width - crop_width) / 2) as i64,
+        ((height - crop_height) / 2) as i64,
+    );
+    DynamicImage::from(canvas)
 }
 
-/// Compress image
-/// - bytes: Image byte array (Uint8Array from frontend)
-/// - quality: Compression quality (0-100, lower means worse quality)
-/// - resize_percent: Size scaling factor (0-1, smaller means smaller size)
-#[wasm_bindgen]
-pub fn compress(bytes: &[u8], quality: u8, resize_percent: f32) -> Result<Vec<u8>, JsError> {
-    // Load image
-    let image = image::load_from_memory(bytes)?;
-    // Resize image (not effective for GIF)
-    let image = resize_image(image, resize_percent);
-    // Get image format
-    let format = image::guess_format(bytes)?;
+/// Make the corners of an image transparent outside of quarter circles, with antialiased edges
+/// - image: Image to process
+/// - radius: Radius of the corners in pixels, clamped to half the shorter side so a square
+///   becomes a circle
+fn round_corners(image: DynamicImage, radius: f32) -> DynamicImage {
+    let mut image = image.into_rgba8();
+    let (width, height) = (image.width() as f32, image.height() as f32);
+    let radius = radius.min(width.min(height) / 2.0);
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        // Distance from the pixel center to the rectangle the corner circles are centered on,
+        // only pixels in the corners are away from it
+        let (center_x, center_y) = (x as f32 + 0.5, y as f32 + 0.5);
+        let dx = (radius - center_x)
+            .max(center_x - (width - radius))
+            .max(0.0);
+        let dy = (radius - center_y)
+            .max(center_y - (height - radius))
+            .max(0.0);
+        if dx == 0.0 && dy == 0.0 {
+            continue;
+        }
+        // The share of the pixel inside the circle, approximated by its distance to the edge
+        let coverage = (radius - dx.hypot(dy) + 0.5).clamp(0.0, 1.0);
+        pixel.0[3] = (pixel.0[3] as f32 * coverage).round() as u8;
+    }
+    DynamicImage::from(image)
+}
 
-    // Final encoded image data
-    let mut output = Vec::new();
+/// Crop the largest centered region of an image that has the given aspect ratio
+/// - image: Image to process
+/// - aspect_width: Aspect ratio width
+/// - aspect_height: Aspect ratio height
+fn crop_to_aspect(image: &DynamicImage, aspect_width: u32, aspect_height: u32) -> DynamicImage {
+    let (width, height) = (image.width(), image.height());
+    let (crop_width, crop_height) = aspect_crop_size(width, height, aspect_width, aspect_height);
+    image.crop_imm(
+        width.saturating_sub(crop_width) / 2,
+        height.saturating_sub(crop_height) / 2,
+        crop_width,
+        crop_height,
+    )
+}
 
-    match format {
-        ImageFormat::Png => {
-            // Quantify PNG image
-            quantify_png_with_color_index(image, quality, &mut output)?;
+/// Size of the largest region of an image that has the given aspect ratio
+fn aspect_crop_size(width: u32, height: u32, aspect_width: u32, aspect_height: u32) -> (u32, u32) {
+    let (width, height) = (width as u64, height as u64);
+    let (aspect_width, aspect_height) = (aspect_width as u64, aspect_height as u64);
+    let (crop_width, crop_height) = if width * aspect_height > height * aspect_width {
+        // Wider than the aspect ratio, cut the sides
+        ((height * aspect_width / aspect_height).max(1), height)
+    } else {
+        // Taller than the aspect ratio, cut the top and bottom
+        (width, (width * aspect_height / aspect_width).max(1))
+    };
+    (crop_width as u32, crop_height as u32)
+}
+
+/// Resize image to a target size
+/// - image: Image to process
+/// - width: Target width (0 for auto from the aspect ratio)
+/// - height: Target height (0 for auto from the aspect ratio)
+/// - mode: How the image is fitted into the target size
+/// - resampling: Resize settings
+fn resize_image_to(
+    image: DynamicImage,
+    width: u32,
+    height: u32,
+    mode: ResizeMode,
+    resampling: Resampling,
+) -> DynamicImage {
+    let (image_width, image_height) = (image.width().max(1) as f64, image.height().max(1) as f64);
+    // A missing dimension follows the aspect ratio, which makes all modes equal
+    let (width, height) = match (width, height) {
+        (0, height) => (
+            ((image_width * height as f64 / image_height).round() as u32).max(1),
+            height,
+        ),
+        (width, 0) => (
+            width,
+            ((image_height * width as f64 / image_width).round() as u32).max(1),
+        ),
+        size => size,
+    };
+    if (width, height) == (image.width(), image.height()) {
+        return image;
+    }
+    // Size the image is scaled to, before `Fill` crops it
+    let (scaled_width, scaled_height) = match mode {
+        ResizeMode::Exact => (width, height),
+        ResizeMode::Fit | ResizeMode::Fill => {
+            let (width_ratio, height_ratio) =
+                (width as f64 / image_width, height as f64 / image_height);
+            let ratio = if mode == ResizeMode::Fit {
+                width_ratio.min(height_ratio)
+            } else {
+                width_ratio.max(height_ratio)
+            };
+            (
+                (image_width * ratio).round() as u32,
+                (image_height * ratio).round() as u32,
+            )
         }
-        ImageFormat::Jpeg | ImageFormat::WebP => {
-            let quality = (quality as f32 * 0.75) as u8;
-            let mut encoder = JpegEncoder::new_with_quality(&mut output, quality);
-            encoder.write_image(
-                image.as_bytes(),
-                image.width(),
-                image.height(),
-                ExtendedColorType::from(image.color()),
-            )?;
+    };
+    let filter = resampling.filter.into();
+    resize_in_light(image, resampling.linear, |image| {
+        if resampling.is_area(&image, scaled_width, scaled_height) {
+            let image = area_resize(&image, scaled_width, scaled_height);
+            return if mode == ResizeMode::Fill {
+                // Crop the overflow around the center, like `resize_to_fill`
+                image.crop_imm(
+                    scaled_width.saturating_sub(width) / 2,
+                    scaled_height.saturating_sub(height) / 2,
+                    width,
+                    height,
+                )
+            } else {
+                image
+            };
         }
-        ImageFormat::Gif => {
-            let decoder = GifDecoder::new(Cursor::new(bytes))?;
-            let frames = decoder.into_frames();
-            let frames = frames.collect_frames()?;
-
-            let frames = frames
-                .into_iter()
-                .map(|frame| {
-                    let image = frame.into_buffer();
-                    let image = DynamicImage::from(image);
-                    let image = resize_image(image, resize_percent);
-                    let image = quantify_png_with_rgba(image, quality).unwrap();
-                    Frame::new(image)
-                })
-                .collect::<Vec<_>>();
-
-            let mut encoder = GifEncoder::new(&mut output);
-            encoder.set_repeat(Repeat::Infinite)?;
-            encoder.encode_frames(frames.into_iter())?;
+        let image = resampling.halve(image, scaled_width, scaled_height);
+        match mode {
+            ResizeMode::Fit => image.resize(width, height, filter),
+            ResizeMode::Fill => image.resize_to_fill(width, height, filter),
+            ResizeMode::Exact => image.resize_exact(width, height, filter),
         }
-        _ => {
-            return Err(JsError::new("Unsupported image format"));
+    })
+}
+
+/// Encode images as the pages of a TIFF
+/// - pages: Images to encode, one page each
+/// - compression: TIFF compression
+/// - output: Output writer
+fn encode_tiff<W: Write + Seek>(
+    pages: &[DynamicImage],
+    compression: TiffCompression,
+    output: W,
+) -> Result<(), Error> {
+    let mut encoder = tiff::encoder::TiffEncoder::new(output)?;
+    for page in pages {
+        match compression {
+            TiffCompression::None => write_tiff_page(&mut encoder, page, Uncompressed)?,
+            TiffCompression::Lzw => write_tiff_page(&mut encoder, page, Lzw)?,
+            TiffCompression::Deflate => write_tiff_page(&mut encoder, page, Deflate::default())?,
         }
     }
+    Ok(())
+}
 
-    if output.len() > bytes.len() {
-        return Ok(bytes.to_vec());
+/// Write an image as one TIFF page with 8-bit samples, with an alpha channel only when some
+/// pixel is transparent and a single channel when gray
+fn write_tiff_page<W: Write + Seek, D: TiffCompressionMethod>(
+    encoder: &mut tiff::encoder::TiffEncoder<W>,
+    image: &DynamicImage,
+    compression: D,
+) -> Result<(), Error> {
+    let (width, height) = (image.width(), image.height());
+    if !is_opaque(image) {
+        let image = image.to_rgba8();
+        encoder.write_image_with_compression::<colortype::RGBA8, D>(
+            width,
+            height,
+            compression,
+            image.as_raw(),
+        )?;
+    } else if is_grayscale(image) {
+        let image = image.to_luma8();
+        encoder.write_image_with_compression::<colortype::Gray8, D>(
+            width,
+            height,
+            compression,
+            image.as_raw(),
+        )?;
+    } else {
+        let image = image.to_rgb8();
+        encoder.write_image_with_compression::<colortype::RGB8, D>(
+            width,
+            height,
+            compression,
+            image.as_raw(),
+        )?;
     }
+    Ok(())
+}
 
-    Ok(output)
+/// Encode JPEG image
+/// - image: Image to process
+/// - options: Compression options
+/// - metadata: ICC profile and EXIF to write
+/// - output: Output writer
+fn encode_jpeg<W: Write>(
+    image: DynamicImage,
+    options: &CompressOptions,
+    metadata: &Metadata,
+    mut output: W,
+) -> Result<(), Error> {
+    // JPEG has no alpha channel, so transparent pixels are blended onto the background
+    let image = if image.color().has_alpha() {
+        flatten_alpha(image, options.background_color()?)
+    } else {
+        image
+    };
+    // JPEG only has 8-bit samples, gray images are written with a single channel
+    let (image, color_type) = match image.color() {
+        image::ColorType::L8 => (image, jpeg_encoder::ColorType::Luma),
+        _ if is_grayscale(&image) => (
+            DynamicImage::from(image.into_luma8()),
+            jpeg_encoder::ColorType::Luma,
+        ),
+        image::ColorType::Rgb8 => (image, jpeg_encoder::ColorType::Rgb),
+        _ => (
+            DynamicImage::from(image.into_rgb8()),
+            jpeg_encoder::ColorType::Rgb,
+        ),
+    };
+    let (width, height) = (
+        u16::try_from(image.width())
+            .map_err(|_| Error::new(ErrorCode::EncodeFailed, "Image is too wide for JPEG"))?,
+        u16::try_from(image.height())
+            .map_err(|_| Error::new(ErrorCode::EncodeFailed, "Image is too tall for JPEG"))?,
+    );
+
+    let mut encoded = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut encoded, options.quality);
+    encoder.set_sampling_factor(options.chroma_subsampling.into());
+    encoder.set_progressive(options.progressive);
+    // With fitted tables the encoder writes baseline JPEG as one scan per component, which the
+    // `image` decoder misreads for subsampled chroma, so baseline output is written with the
+    // standard tables and gets its fitted ones from a second pass below
+    encoder.set_optimized_huffman_tables(options.optimize_huffman && options.progressive);
+    if let Some(icc_profile) = &metadata.icc_profile {
+        encoder.add_icc_profile(icc_profile)?;
+    }
+    if let Some(exif) = &metadata.exif {
+        encoder.add_exif_metadata(exif)?;
+    }
+    if let Some(dpi) = metadata.dpi {
+        encoder.set_density(jpeg_encoder::PixelDensity::dpi(dpi));
+    }
+    encoder.encode(image.as_bytes(), width, height, color_type)?;
+    if options.optimize_huffman && !options.progressive {
+        encoded = jpeg_transform::optimize(&encoded)?;
+    }
+    output.write_all(&encoded)?;
+
+    Ok(())
 }
 
-fn resize_image(image: DynamicImage, resize_percent: f32) -> DynamicImage {
-    if resize_percent == 1.0 {
-        return image;
+/// Encode WebP image, keeping the alpha channel
+/// - image: Image to process
+/// - options: Compression options
+/// - metadata: ICC profile and EXIF to write
+/// - output: Output writer
+///
+/// The WebP encoder only supports lossless output, so below 100 the image is quantified first
+/// to reduce the number of colors the encoder has to store. Returns the achieved quantization
+/// quality in that case. With `webp_lossless`, the image is never quantified, only
+/// preprocessed by `near_lossless`.
+fn encode_webp<W: Write>(
+    image: DynamicImage,
+    options: &CompressOptions,
+    metadata: &Metadata,
+    output: W,
+) -> Result<Option<u8>, Error> {
+    let (image, quantization_quality) = if options.webp_lossless {
+        let mut image = image.into_rgba8();
+        webp::near_lossless(&mut image, options.near_lossless);
+        (image, None)
+    } else if options.quality < 100 {
+        quantify_png_with_rgba(image, options)?
+    } else {
+        (image.into_rgba8(), None)
+    };
+
+    let mut encoder = image_webp::WebPEncoder::new(output);
+    if let Some(icc_profile) = &metadata.icc_profile {
+        encoder.set_icc_profile(icc_profile.clone());
     }
-    let (width, height) = (image.width(), image.height());
-    let new_width = (width as f32 * resize_percent) as u32;
-    let new_height = (height as f32 * resize_percent) as u32;
-    image.resize(new_width, new_height, image::imageops::FilterType::Nearest)
+    if let Some(exif) = &metadata.exif {
+        encoder.set_exif_metadata(exif.clone());
+    }
+    encoder.encode(
+        image.as_bytes(),
+        image.width(),
+        image.height(),
+        image_webp::ColorType::Rgba8,
+    )?;
+
+    Ok(quantization_quality)
+}
+
+/// Encode the frames of an animation as animated WebP, returning the worst achieved quality
+/// - frames: Frames to encode, all of the same size
+/// - repeat: Loop count
+/// - options: Compression options
+/// - metadata: ICC profile and EXIF to write
+/// - output: Output writer
+///
+/// Like still WebP, frames are lossless and quantified first below quality 100.
+fn encode_animated_webp<W: Write>(
+    frames: &[Frame],
+    repeat: Repeat,
+    options: &CompressOptions,
+    metadata: &Metadata,
+    mut output: W,
+) -> Result<Option<u8>, Error> {
+    let (width, height) = frames[0].buffer().dimensions();
+
+    let mut quality = None;
+    let frames = frames
+        .iter()
+        .map(|frame| {
+            let image = DynamicImage::from(frame.buffer().clone());
+            // Metadata goes into the animation, not into every frame
+            let mut webp = Vec::new();
+            let frame_quality = encode_webp(image, options, &Metadata::default(), &mut webp)?;
+            quality = quality.into_iter().chain(frame_quality).min();
+
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            Ok(webp::AnimationFrame {
+                webp,
+                duration_ms: (numer as f64 / denom as f64).round() as u32,
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let loop_count = repeat_plays(repeat).min(u16::MAX as u32) as u16;
+    let animation = webp::assemble_animation(width, height, &frames, loop_count, metadata);
+    output.write_all(&animation)?;
+
+    Ok(quality)
 }
 
-/// Quantify PNG image using direct RGBA values
+/// Quantify PNG image using direct RGBA values, returning the image and achieved quality
 /// - image: Image to process
-/// - quality: Compression quality (0-100, lower means worse quality)
-fn quantify_png_with_rgba(image: DynamicImage, quality: u8) -> Result<image::RgbaImage, JsError> {
+/// - options: Compression options
+fn quantify_png_with_rgba(
+    image: DynamicImage,
+    options: &CompressOptions,
+) -> Result<(image::RgbaImage, Option<u8>), Error> {
     let (width, height) = (image.width(), image.height());
-    let (palette, pixels) = quantify_and_get_platte_and_indexes(image, quality)?;
+    let quantized = quantify_and_get_platte_and_indexes(image, options)?;
+    let quality = quantized.quality;
 
-    let mut buf = Vec::with_capacity(pixels.len());
+    Ok((palette_image(quantized, width, height), quality))
+}
+
+/// Look up the palette color of every pixel of a quantized image
+/// - quantized: Palette and indexes of the image
+/// - width: Image width
+/// - height: Image height
+fn palette_image(quantized: Quantized, width: u32, height: u32) -> image::RgbaImage {
+    let Quantized {
+        palette,
+        indexes: pixels,
+        ..
+    } = quantized;
+
+    let mut buf = Vec::with_capacity(pixels.len() * 4);
     for index in pixels {
         // Get color from palette and convert to RGBA
         let rgba = palette[index as usize];
         buf.extend_from_slice(&[rgba.r, rgba.g, rgba.b, rgba.a]);
     }
 
-    let rgba_image =
-        image::RgbaImage::from_vec(width, height, buf).expect("Failed to create image");
-
-    Ok(rgba_image)
+    image::RgbaImage::from_vec(width, height, buf).expect("Failed to create image")
 }
 
-/// Quantify PNG image using palette + index method
+/// Quantify PNG image using palette + index method, returning the achieved quality
 /// - image: Image to process
-/// - quality: Compression quality (0-100, lower means worse quality)
+/// - options: Compression options
+/// - metadata: ICC profile and EXIF to write
 /// - output: Output writer
 fn quantify_png_with_color_index<W: Write>(
     image: DynamicImage,
-    quality: u8,
+    options: &CompressOptions,
+    metadata: &Metadata,
     output: W,
-) -> Result<(), JsError> {
+) -> Result<Option<u8>, Error> {
     let (width, height) = (image.width(), image.height());
 
-    let (palette, indexes) = quantify_and_get_platte_and_indexes(image, quality)?;
+    let quantized = sort_palette(quantify_and_get_platte_and_indexes(image, options)?);
+    let quality = quantized.quality;
+
+    let (encoder, data) = indexed_png_encoder(
+        output,
+        width,
+        height,
+        options,
+        metadata,
+        quantized,
+        options.interlace,
+    )?;
+    let mut writer = encoder.write_header()?;
+    if options.interlace {
+        write_interlaced_data(&mut writer, &data, options)?;
+    } else {
+        writer.write_image_data(&data)?;
+    }
+
+    Ok(quality)
+}
+
+/// Encode a 16-bit image as lossless PNG with 16 bits per channel
+/// - image: Image to process, with 16-bit channels
+/// - options: Compression options
+/// - metadata: ICC profile and EXIF to write
+/// - output: Output writer
+fn encode_png_16_bit<W: Write>(
+    image: DynamicImage,
+    options: &CompressOptions,
+    metadata: &Metadata,
+    output: W,
+) -> Result<(), Error> {
+    let color_type = match image.color() {
+        image::ColorType::L16 => png::ColorType::Grayscale,
+        image::ColorType::La16 => png::ColorType::GrayscaleAlpha,
+        image::ColorType::Rgb16 => png::ColorType::Rgb,
+        _ => png::ColorType::Rgba,
+    };
+    let image = match image.color() {
+        image::ColorType::L16 | image::ColorType::La16 | image::ColorType::Rgb16 => image,
+        _ => DynamicImage::from(image.into_rgba16()),
+    };
+
+    let info = png_info(image.width(), image.height(), options, metadata);
+
+    let mut encoder = png::Encoder::with_info(output, info)?;
+    encoder.set_color(color_type);
+    encoder.set_depth(png::BitDepth::Sixteen);
+    // Unlike palette indexes, truecolor samples shrink a lot with filtering
+    let (compression, ..) = options.png_compression.settings();
+    encoder.set_compression(compression);
+    encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive);
+
+    // PNG stores samples big endian
+    let data = image
+        .as_bytes()
+        .chunks_exact(2)
+        .flat_map(|sample| u16::from_ne_bytes([sample[0], sample[1]]).to_be_bytes())
+        .collect::<Vec<_>>();
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+
+    Ok(())
+}
+
+/// Encode an opaque image as a truecolor PNG with 8-bit RGB samples, or a grayscale PNG when
+/// every pixel is gray
+/// - image: Image to process
+/// - options: Compression options
+/// - metadata: ICC profile and EXIF to write
+/// - output: Output writer
+fn encode_png_rgb<W: Write>(
+    image: DynamicImage,
+    options: &CompressOptions,
+    metadata: &Metadata,
+    output: W,
+) -> Result<(), Error> {
+    let (image, color_type) = if is_grayscale(&image) {
+        (
+            DynamicImage::from(image.into_luma8()),
+            png::ColorType::Grayscale,
+        )
+    } else {
+        (DynamicImage::from(image.into_rgb8()), png::ColorType::Rgb)
+    };
+    let info = png_info(image.width(), image.height(), options, metadata);
+
+    let mut encoder = png::Encoder::with_info(output, info)?;
+    encoder.set_color(color_type);
+    encoder.set_depth(png::BitDepth::Eight);
+    let (compression, ..) = options.png_compression.settings();
+    encoder.set_compression(compression);
+    encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(image.as_bytes())?;
+
+    Ok(())
+}
+
+/// Quantify the frames of an animation with a shared palette and write them as APNG, returning
+/// the achieved quality
+/// - frames: Frames to encode, all of the same size
+/// - repeat: Loop count
+/// - options: Compression options
+/// - metadata: ICC profile and EXIF to write
+/// - output: Output writer
+fn quantify_apng_with_color_index<W: Write>(
+    frames: &[Frame],
+    repeat: Repeat,
+    options: &CompressOptions,
+    metadata: &Metadata,
+    output: W,
+) -> Result<Option<u8>, Error> {
+    let (width, height) = frames[0].buffer().dimensions();
+
+    // APNG has a single palette, shared by all frames. Their indexes are written as the rows of
+    // one tall image, then split back into frames.
+    let images = frames.iter().map(Frame::buffer).collect::<Vec<_>>();
+    let SharedQuantized {
+        palette,
+        indexes,
+        quality,
+    } = quantize_shared(&images, options.quantization(), |done| {
+        report_progress(options, done, frames.len())
+    })?;
+    let quantized = sort_palette(Quantized {
+        palette,
+        indexes: indexes.concat(),
+        quality,
+    });
+
+    let (mut encoder, data) =
+        indexed_png_encoder(output, width, height, options, metadata, quantized, false)?;
+    encoder.set_animated(frames.len() as u32, repeat_plays(repeat))?;
+    let mut writer = encoder.write_header()?;
+    for (frame, data) in frames
+        .iter()
+        .zip(data.chunks_exact(data.len() / frames.len()))
+    {
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let delay_ms = (numer as f64 / denom as f64).round().min(u16::MAX as f64) as u16;
+        writer.set_frame_delay(delay_ms, 1000)?;
+        writer.write_image_data(data)?;
+    }
+    writer.finish()?;
+
+    Ok(quality)
+}
+
+/// Smallest alpha written as opaque in GIF output, lower alpha becomes fully transparent
+const GIF_OPAQUE_ALPHA: u8 = 128;
+
+/// Quantify the frames of a GIF with one global palette and write them, returning the achieved
+/// quality and the stats of every frame
+/// - frames: Frames to encode, inside the canvas
+/// - gif: Canvas and loop count
+/// - options: Compression options
+/// - output: Output data
+///
+/// GIF has a single transparent color, so pixels that are more than half transparent, in the
+/// input or in their palette entry, all take one entry.
+fn quantify_gif_with_global_palette(
+    frames: &[Frame],
+    gif: &GifCanvas,
+    options: &CompressOptions,
+    output: &mut Vec<u8>,
+) -> Result<(Option<u8>, Vec<GifFrameStats>), Error> {
+    let images = frames.iter().map(Frame::buffer).collect::<Vec<_>>();
+    // Keep a palette entry free for the transparent color
+    let has_transparency = images
+        .iter()
+        .any(|image| image.pixels().any(|pixel| pixel.0[3] < GIF_OPAQUE_ALPHA));
+    let mut settings = options.quantization();
+    if has_transparency {
+        settings.max_colors = settings.max_colors.min(255);
+    }
+    let SharedQuantized {
+        mut palette,
+        mut indexes,
+        quality,
+    } = quantize_shared(&images, settings, |done| {
+        report_progress(options, done, frames.len())
+    })?;
 
-    // RGB palette
+    let transparent = has_transparency.then(|| {
+        let transparent = palette
+            .iter()
+            .position(|rgba| rgba.a < GIF_OPAQUE_ALPHA)
+            .unwrap_or_else(|| {
+                palette.push(RGBA::new(0, 0, 0, 0));
+                palette.len() - 1
+            });
+        for (indexes, image) in indexes.iter_mut().zip(&images) {
+            for (index, pixel) in indexes.iter_mut().zip(image.pixels()) {
+                if pixel.0[3] < GIF_OPAQUE_ALPHA || palette[*index as usize].a < GIF_OPAQUE_ALPHA {
+                    *index = transparent as u8;
+                }
+            }
+        }
+        transparent
+    });
     let rgb_palette = palette
         .iter()
         .flat_map(|rgba| [rgba.r, rgba.g, rgba.b])
         .collect::<Vec<_>>();
-    // Alpha channel values
-    let alpha_values = palette.iter().map(|rgba| rgba.a).collect::<Vec<u8>>();
 
-    let mut encoder = png::Encoder::new(output, width, height);
-    encoder.set_palette(rgb_palette);
-    encoder.set_trns(alpha_values);
-    encoder.set_color(png::ColorType::Indexed);
-    encoder.set_depth(png::BitDepth::Eight);
-    encoder.set_compression(png::Compression::Best);
-    encoder.set_filter(png::FilterType::NoFilter);
-    encoder.set_adaptive_filter(png::AdaptiveFilterType::NonAdaptive);
+    let mut encoder = gif.encoder(output, &rgb_palette)?;
+    let mut stats = Vec::with_capacity(frames.len());
+    for (frame, indexes) in frames.iter().zip(indexes) {
+        let start = encoder.get_ref().len();
+        encoder.write_frame(&gif::Frame {
+            transparent: transparent.map(|transparent| transparent as u8),
+            buffer: Cow::Owned(indexes),
+            ..gif.frame(frame)?
+        })?;
+        stats.push(GifFrameStats {
+            size: (encoder.get_ref().len() - start) as u32,
+            colors: palette.len() as u16,
+        });
+    }
 
-    let mut writer = encoder.write_header()?;
-    writer.write_image_data(&indexes)?;
+    Ok((quality, stats))
+}
+
+/// Encoded size and palette size of a written GIF frame
+struct GifFrameStats {
+    /// Bytes of the frame, from its graphic control extension to its last data block
+    size: u32,
+    /// Number of colors of the palette the frame uses
+    colors: u16,
+}
+
+/// Canvas of a GIF being written
+struct GifCanvas {
+    width: u32,
+    height: u32,
+    /// Loop count, not set for a still image, which doesn't loop
+    repeat: Option<Repeat>,
+    /// Whether every frame is drawn over the previous one, as for delta frames, instead of the
+    /// canvas being cleared first
+    keep_frames: bool,
+}
+
+impl GifCanvas {
+    /// Create a GIF encoder writing the header of the canvas
+    /// - output: Output writer
+    /// - palette: Global RGB palette, empty when every frame has its own
+    fn encoder<W: Write>(&self, output: W, palette: &[u8]) -> Result<gif::Encoder<W>, Error> {
+        let mut encoder = gif::Encoder::new(
+            output,
+            gif_dimension(self.width)?,
+            gif_dimension(self.height)?,
+            palette,
+        )?;
+        if let Some(repeat) = self.repeat {
+            encoder.set_repeat(match repeat {
+                Repeat::Infinite => gif::Repeat::Infinite,
+                Repeat::Finite(count) => gif::Repeat::Finite(count),
+            })?;
+        }
+        Ok(encoder)
+    }
+
+    /// Frame header with the size, offset, delay and disposal of a frame, without pixels
+    ///
+    /// Decoded frames cover the whole canvas, so each is cleared to the background before the
+    /// next one, and transparent pixels stay transparent instead of showing the previous frame.
+    /// Delta frames are kept instead, as they only hold what changed.
+    fn frame(&self, frame: &Frame) -> Result<gif::Frame<'static>, Error> {
+        let (width, height) = frame.buffer().dimensions();
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let delay_cs = (numer as f64 / denom as f64 / 10.0)
+            .round()
+            .min(u16::MAX as f64) as u16;
+        Ok(gif::Frame {
+            left: gif_dimension(frame.left())?,
+            top: gif_dimension(frame.top())?,
+            width: gif_dimension(width)?,
+            height: gif_dimension(height)?,
+            delay: delay_cs,
+            dispose: if self.keep_frames {
+                gif::DisposalMethod::Keep
+            } else {
+                gif::DisposalMethod::Background
+            },
+            ..gif::Frame::default()
+        })
+    }
+}
+
+/// Check that a size or offset fits the 16 bits of GIF
+fn gif_dimension(value: u32) -> Result<u16, Error> {
+    u16::try_from(value)
+        .map_err(|_| Error::new(ErrorCode::EncodeFailed, "Image is too large for GIF"))
+}
+
+/// Write quantized GIF frames, each with its own palette, returning the stats of every frame
+/// - frames: Frames with at most 256 colors, each either opaque or fully transparent, inside
+///   the canvas
+/// - gif: Canvas and loop count
+/// - output: Output data
+fn write_gif_frames(
+    frames: impl Iterator<Item = Frame>,
+    gif: &GifCanvas,
+    output: &mut Vec<u8>,
+) -> Result<Vec<GifFrameStats>, Error> {
+    let mut encoder = gif.encoder(output, &[])?;
+    let mut stats = Vec::new();
+    for frame in frames {
+        let header = gif.frame(&frame)?;
+        let mut pixels = frame.into_buffer().into_raw();
+        // The speed only matters above 256 colors, which quantized frames never have
+        let indexed = gif::Frame::from_rgba_speed(header.width, header.height, &mut pixels, 1);
+        let colors = indexed
+            .palette
+            .as_ref()
+            .map_or(0, |palette| palette.len() / 3) as u16;
+        let start = encoder.get_ref().len();
+        encoder.write_frame(&gif::Frame {
+            palette: indexed.palette,
+            transparent: indexed.transparent,
+            buffer: indexed.buffer,
+            ..header
+        })?;
+        stats.push(GifFrameStats {
+            size: (encoder.get_ref().len() - start) as u32,
+            colors,
+        });
+    }
+    Ok(stats)
+}
+
+/// Turn full size opaque frames into delta frames, holding only the pixels that changed from
+/// the previous frame
+/// - frames: Frames of the animation, each covering the whole canvas
+///
+/// Every frame after the first is cropped to the changed pixels, and unchanged pixels inside
+/// that area are made transparent, so the previous frame shows through once frames are kept.
+/// Returns `None` when a frame is transparent, as its transparent pixels would no longer clear
+/// the previous frame, or doesn't cover the canvas.
+fn delta_frames(frames: &[Frame]) -> Option<Vec<Frame>> {
+    let (width, height) = frames.first()?.buffer().dimensions();
+    let is_full_opaque = |frame: &Frame| {
+        frame.buffer().dimensions() == (width, height)
+            && frame.left() == 0
+            && frame.top() == 0
+            && frame.buffer().pixels().all(|pixel| pixel.0[3] == 255)
+    };
+    if !frames.iter().all(is_full_opaque) {
+        return None;
+    }
+
+    let mut delta = vec![frames[0].clone()];
+    for pair in frames.windows(2) {
+        let (previous, current) = (pair[0].buffer(), pair[1].buffer());
+        let changed = |x: u32, y: u32| previous.get_pixel(x, y) != current.get_pixel(x, y);
+
+        let mut bounds: Option<(u32, u32, u32, u32)> = None;
+        for y in 0..height {
+            for x in 0..width {
+                if changed(x, y) {
+                    bounds = Some(match bounds {
+                        Some((left, top, right, bottom)) => {
+                            (left.min(x), top.min(y), right.max(x), bottom.max(y))
+                        }
+                        None => (x, y, x, y),
+                    });
+                }
+            }
+        }
+        // An unchanged frame still takes its delay, as a single transparent pixel
+        let (left, top, right, bottom) = bounds.unwrap_or((0, 0, 0, 0));
+        let image = image::RgbaImage::from_fn(right - left + 1, bottom - top + 1, |x, y| {
+            let (x, y) = (left + x, top + y);
+            if changed(x, y) {
+                *current.get_pixel(x, y)
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        });
+        delta.push(Frame::from_parts(image, left, top, pair[1].delay()));
+    }
+    Some(delta)
+}
+
+/// Create a PNG encoder for a quantified image, returning it with the image data to write
+/// - output: Output writer
+/// - width: Image width
+/// - height: Image height
+/// - options: Compression options
+/// - metadata: ICC profile and EXIF to write
+/// - quantized: Palette and indexes of the image
+/// - interlace: Whether to interlace the image, then the data holds the filtered Adam7 passes to
+///   write with `write_interlaced_data`, as the encoder only writes non-interlaced rows
+fn indexed_png_encoder<'a, W: Write>(
+    output: W,
+    width: u32,
+    height: u32,
+    options: &CompressOptions,
+    metadata: &'a Metadata,
+    quantized: Quantized,
+    interlace: bool,
+) -> Result<(png::Encoder<'a, W>, Vec<u8>), Error> {
+    let Quantized {
+        palette, indexes, ..
+    } = quantized;
+
+    let mut info = png_info(width, height, options, metadata);
+    info.interlaced = interlace;
+
+    let mut encoder = png::Encoder::with_info(output, info)?;
+
+    // An opaque gray palette is written as plain grayscale, saving the PLTE and tRNS chunks
+    let grayscale = palette
+        .iter()
+        .all(|rgba| rgba.r == rgba.g && rgba.g == rgba.b && rgba.a == 255);
+    let (data, depth) = if grayscale {
+        encoder.set_color(png::ColorType::Grayscale);
+        // Levels below 8 bits are spread evenly over 0-255, like 0, 85, 170 and 255 for 2 bits
+        let depth = [1, 2, 4]
+            .into_iter()
+            .find(|&depth| {
+                let step = 255 / ((1 << depth) - 1);
+                palette.iter().all(|rgba| rgba.r % step == 0)
+            })
+            .unwrap_or(8);
+        let step = 255 / ((1u16 << depth) - 1) as u8;
+        let levels = indexes
+            .iter()
+            .map(|&index| palette[index as usize].r / step)
+            .collect::<Vec<_>>();
+        (levels, depth)
+    } else {
+        // RGB palette
+        let rgb_palette = palette
+            .iter()
+            .flat_map(|rgba| [rgba.r, rgba.g, rgba.b])
+            .collect::<Vec<_>>();
+        // Alpha channel values, entries after the last transparent one default to opaque
+        let opaque_tail = palette
+            .iter()
+            .rev()
+            .take_while(|rgba| rgba.a == 255)
+            .count();
+        let alpha_values = palette[..palette.len() - opaque_tail]
+            .iter()
+            .map(|rgba| rgba.a)
+            .collect::<Vec<u8>>();
+
+        encoder.set_palette(rgb_palette);
+        if !alpha_values.is_empty() {
+            encoder.set_trns(alpha_values);
+        }
+        encoder.set_color(png::ColorType::Indexed);
+        // Fewest bits per pixel that can address every palette entry
+        let depth = [1, 2, 4]
+            .into_iter()
+            .find(|&depth| palette.len() <= 1 << depth)
+            .unwrap_or(8);
+        (indexes, depth)
+    };
+    let data = if interlace {
+        adam7_passes(&data, width, height, depth)
+    } else {
+        pack_samples(data, width, depth)
+    };
+    encoder.set_depth(match depth {
+        1 => png::BitDepth::One,
+        2 => png::BitDepth::Two,
+        4 => png::BitDepth::Four,
+        _ => png::BitDepth::Eight,
+    });
+    let (compression, filter, adaptive_filter) = options.png_compression.settings();
+    encoder.set_compression(compression);
+    encoder.set_filter(filter);
+    encoder.set_adaptive_filter(adaptive_filter);
+
+    Ok((encoder, data))
+}
+
+/// PNG header of an image, with its metadata and color space
+/// - width: Image width
+/// - height: Image height
+/// - options: Compression options
+/// - metadata: ICC profile, EXIF and resolution to write
+///
+/// An ICC profile describes the colors itself, so the sRGB chunk is only written without one.
+fn png_info<'a>(
+    width: u32,
+    height: u32,
+    options: &CompressOptions,
+    metadata: &'a Metadata,
+) -> png::Info<'a> {
+    let mut info = png::Info::with_size(width, height);
+    info.icc_profile = metadata.icc_profile.as_deref().map(Cow::Borrowed);
+    info.exif_metadata = metadata.exif.as_deref().map(Cow::Borrowed);
+    info.pixel_dims = metadata.dpi.map(png_pixel_dims);
+    if let Some(intent) = options.srgb_intent
+        && metadata.icc_profile.is_none()
+    {
+        info.srgb = Some(intent.into());
+    }
+    for (keyword, text) in &options.png_text {
+        if is_latin1(text) {
+            info.uncompressed_latin1_text
+                .push(png::text_metadata::TEXtChunk::new(keyword, text));
+        } else {
+            info.utf8_text
+                .push(png::text_metadata::ITXtChunk::new(keyword, text));
+        }
+    }
+    info
+}
+
+/// Whether every character of a string fits Latin-1, the encoding of PNG `tEXt` chunks
+fn is_latin1(text: &str) -> bool {
+    text.chars().all(|char| (char as u32) < 0x100)
+}
+
+/// Physical pixel size of a PNG for a resolution in dots per inch, which PNG stores per meter
+fn png_pixel_dims(dpi: u16) -> png::PixelDimensions {
+    let pixels_per_meter = (dpi as f64 / 0.0254).round() as u32;
+    png::PixelDimensions {
+        xppu: pixels_per_meter,
+        yppu: pixels_per_meter,
+        unit: png::Unit::Meter,
+    }
+}
+
+/// Reorder the palette so transparent entries come first, which keeps the tRNS chunk short
+///
+/// The sort is stable, so the order within opaque and transparent entries is kept: imagequant
+/// orders its palette by popularity, and reordering by luminance instead was measured to grow
+/// the output by 1-2%. As imagequant mostly puts transparent entries first already, this
+/// usually saves a few bytes at most.
+fn sort_palette(quantized: Quantized) -> Quantized {
+    let Quantized {
+        palette,
+        indexes,
+        quality,
+    } = quantized;
+
+    let mut order = (0..palette.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&index| palette[index].a == 255);
+
+    // New index of every old palette entry
+    let mut positions = vec![0; palette.len()];
+    for (position, &index) in order.iter().enumerate() {
+        positions[index] = position as u8;
+    }
+
+    Quantized {
+        palette: order.iter().map(|&index| palette[index]).collect(),
+        indexes: indexes
+            .iter()
+            .map(|&index| positions[index as usize])
+            .collect(),
+        quality,
+    }
+}
+
+/// Pack samples of one byte each into rows of `depth` bits per sample, as PNG stores them
+/// - samples: Samples of every pixel, row by row, each below `1 << depth`
+/// - width: Image width
+/// - depth: Bits per sample: 1, 2, 4 or 8
+///
+/// The first sample takes the most significant bits of a byte, every row starts on a new byte.
+fn pack_samples(samples: Vec<u8>, width: u32, depth: u8) -> Vec<u8> {
+    if depth == 8 || width == 0 {
+        return samples;
+    }
+    let per_byte = (8 / depth) as usize;
+    samples
+        .chunks(width as usize)
+        .flat_map(|row| {
+            row.chunks(per_byte).map(|pixels| {
+                pixels.iter().enumerate().fold(0, |byte, (index, &sample)| {
+                    byte | sample << (8 - depth as usize * (index + 1))
+                })
+            })
+        })
+        .collect()
+}
+
+/// Start column, start row, column step and row step of the seven Adam7 passes
+const ADAM7_PASSES: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
 
+/// Split samples into the Adam7 passes of an interlaced PNG, packed and filtered like PNG stores
+/// them
+/// - samples: Samples of every pixel, row by row, each below `1 << depth`
+/// - width: Image width
+/// - height: Image height
+/// - depth: Bits per sample: 1, 2, 4 or 8
+///
+/// Every row starts with filter type 0, as palette indexes rarely benefit from filtering.
+fn adam7_passes(samples: &[u8], width: u32, height: u32, depth: u8) -> Vec<u8> {
+    let mut data = Vec::with_capacity(samples.len() + height as usize * 2);
+    for (left, top, step_x, step_y) in ADAM7_PASSES {
+        // Passes without pixels are left out, small images have some
+        if left >= width || top >= height {
+            continue;
+        }
+        let pass_width = (width - left).div_ceil(step_x);
+        let pass_samples = (top..height)
+            .step_by(step_y as usize)
+            .flat_map(|y| {
+                (left..width)
+                    .step_by(step_x as usize)
+                    .map(move |x| samples[(y * width + x) as usize])
+            })
+            .collect();
+        let row_length = (pass_width as usize * depth as usize).div_ceil(8);
+        for row in pack_samples(pass_samples, pass_width, depth).chunks(row_length) {
+            data.push(0);
+            data.extend_from_slice(row);
+        }
+    }
+    data
+}
+
+/// Compress the filtered Adam7 passes of `adam7_passes` and write them as image data
+/// - writer: PNG writer, whose header is marked as interlaced
+/// - data: Filtered passes
+/// - options: Compression options
+fn write_interlaced_data<W: Write>(
+    writer: &mut png::Writer<W>,
+    data: &[u8],
+    options: &CompressOptions,
+) -> Result<(), Error> {
+    let level = match options.png_compression {
+        PngCompression::Fast => flate2::Compression::fast(),
+        PngCompression::Default => flate2::Compression::default(),
+        PngCompression::Best => flate2::Compression::best(),
+    };
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), level);
+    encoder.write_all(data)?;
+    let compressed = encoder.finish()?;
+
+    // Chunk lengths are limited to 31 bits
+    for chunk in compressed.chunks(i32::MAX as usize) {
+        writer.write_chunk(png::chunk::IDAT, chunk)?;
+    }
     Ok(())
 }
 
-/// Quantify PNG and get palette and indexes
+/// Quantized image
+struct Quantized {
+    palette: Vec<RGBA>,
+    /// Palette index of each pixel
+    indexes: Vec<u8>,
+    /// Quality the quantization achieved
+    quality: Option<u8>,
+}
+
+/// Quantify PNG and get palette, indexes and the achieved quality
 /// - image: Image to process
-/// - quality: Compression quality (0-100, lower means worse quality)
+/// - options: Compression options
+///
+/// Fails when the achieved quality is below `options.min_quality`.
 fn quantify_and_get_platte_and_indexes(
     image: DynamicImage,
+    options: &CompressOptions,
+) -> Result<Quantized, Error> {
+    quantize(image.into_rgba8(), options.quantization())
+}
+
+/// Quantization settings of the compression options
+///
+/// Unlike the options, which hold a JS function, they can be sent to other threads.
+#[derive(Clone, Copy, Debug)]
+struct Quantization {
     quality: u8,
-) -> Result<(Vec<RGBA>, Vec<u8>), JsError> {
-    let image = image.into_rgba8();
-    let (width, height) = (image.width(), image.height());
+    max_colors: u16,
+    speed: u8,
+    dither_level: f32,
+    min_quality: u8,
+}
 
-    let mut quantizer = imagequant::new();
-    quantizer.set_quality(0, quality)?;
+impl Quantization {
+    /// Create an imagequant quantizer with the quality, colors and speed of the settings
+    fn quantizer(&self) -> Result<imagequant::Attributes, Error> {
+        let mut quantizer = imagequant::new();
+        quantizer.set_quality(0, self.quality)?;
+        quantizer.set_max_colors(self.max_colors as u32)?;
+        quantizer.set_speed(self.speed as i32)?;
+        Ok(quantizer)
+    }
+
+    /// Check the achieved quality of a quantization against `min_quality`
+    fn check_quality(&self, quality: Option<u8>) -> Result<(), Error> {
+        if let Some(quality) = quality
+            && quality < self.min_quality
+        {
+            return Err(Error::new(
+                ErrorCode::QualityTooLow,
+                format!(
+                    "Quantization quality {quality} is below min_quality {}",
+                    self.min_quality
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl CompressOptions {
+    /// Color that transparent pixels are blended onto, white unless `background` is set
+    fn background_color(&self) -> Result<Rgba<u8>, Error> {
+        match &self.background {
+            Some(background) => parse_color(background),
+            None => Ok(Rgba([255, 255, 255, 255])),
+        }
+    }
+
+    /// Quality used for an output format, its own quality when set, `quality` otherwise
+    fn quality_for(&self, format: ImageFormat) -> u8 {
+        let format_quality = match format {
+            ImageFormat::Jpeg => self.jpeg_quality,
+            ImageFormat::WebP => self.webp_quality,
+            ImageFormat::Png => self.png_quality,
+            _ => None,
+        };
+        format_quality.unwrap_or(self.quality)
+    }
+
+    /// Settings used to resize the image
+    fn resampling(&self) -> Resampling {
+        Resampling {
+            filter: self.filter,
+            linear: self.linear_resize,
+            stepped: self.stepped_resize,
+        }
+    }
+
+    /// Settings used to quantize the image
+    fn quantization(&self) -> Quantization {
+        Quantization {
+            quality: self.quality,
+            max_colors: self.max_colors,
+            speed: self.speed,
+            dither_level: self.dither_level,
+            min_quality: self.min_quality,
+        }
+    }
+}
+
+/// Quantize an image
+/// - image: Image to process
+/// - settings: Quantization settings
+///
+/// Images with no more colors than `max_colors` keep their exact colors, whatever the quality.
+fn quantize(image: image::RgbaImage, settings: Quantization) -> Result<Quantized, Error> {
+    if let Some((palette, indexes)) = palette::exact_palette(&image, settings.max_colors as usize) {
+        return Ok(Quantized {
+            palette,
+            indexes,
+            quality: Some(100),
+        });
+    }
+
+    let quantizer = settings.quantizer()?;
+    // Quantified image
+    let mut q_img = quant_image(&quantizer, &image)?;
+
+    // Perform quantization
+    let mut res = quantizer.quantize(&mut q_img)?;
+    res.set_dithering_level(settings.dither_level)?;
+
+    // Palette and indexes
+    let (palette, indexes) = res.remapped(&mut q_img)?;
+
+    let quality = res.quantization_quality();
+    settings.check_quality(quality)?;
+
+    Ok(Quantized {
+        palette,
+        indexes,
+        quality,
+    })
+}
+
+/// Images quantized to one shared palette
+struct SharedQuantized {
+    palette: Vec<RGBA>,
+    /// Palette index of each pixel, per image
+    indexes: Vec<Vec<u8>>,
+    /// Quality the quantization achieved
+    quality: Option<u8>,
+}
 
+/// Quantize images to one shared palette, like the frames of an animation
+/// - images: Images to quantize together
+/// - settings: Quantization settings
+/// - on_remapped: Called with the number of images done after each is remapped to the palette
+///
+/// The colors of every image are added to one histogram that the palette is built from, then
+/// each image is remapped on its own, so the images are never copied into one. Images with no
+/// more colors than `max_colors` together keep their exact colors, whatever the quality.
+fn quantize_shared(
+    images: &[&image::RgbaImage],
+    settings: Quantization,
+    mut on_remapped: impl FnMut(usize) -> Result<(), Error>,
+) -> Result<SharedQuantized, Error> {
+    if let Some((palette, indexes)) =
+        palette::exact_shared_palette(images, settings.max_colors as usize)
+    {
+        for done in 1..=images.len() {
+            on_remapped(done)?;
+        }
+        return Ok(SharedQuantized {
+            palette,
+            indexes,
+            quality: Some(100),
+        });
+    }
+
+    let quantizer = settings.quantizer()?;
+    let mut histogram = imagequant::Histogram::new(&quantizer);
+    for image in images {
+        histogram.add_image(&quantizer, &mut quant_image(&quantizer, image)?)?;
+    }
+    // The palette of a histogram isn't refined while remapping, so every image gets the same
+    let mut res = histogram.quantize(&quantizer)?;
+    res.set_dithering_level(settings.dither_level)?;
+    let quality = res.quantization_quality();
+    settings.check_quality(quality)?;
+
+    let mut palette = Vec::new();
+    let mut indexes = Vec::with_capacity(images.len());
+    for image in images {
+        let (image_palette, image_indexes) = res.remapped(&mut quant_image(&quantizer, image)?)?;
+        palette = image_palette;
+        indexes.push(image_indexes);
+        on_remapped(indexes.len())?;
+    }
+
+    Ok(SharedQuantized {
+        palette,
+        indexes,
+        quality,
+    })
+}
+
+/// Copy an image into the pixel type of imagequant
+fn quant_image(
+    quantizer: &imagequant::Attributes,
+    image: &image::RgbaImage,
+) -> Result<QImage<'static>, Error> {
     let rgba_data: Vec<RGBA> = image
         .as_bytes()
         .chunks_exact(4)
@@ -166,13 +4973,11 @@ fn quantify_and_get_platte_and_indexes(
             a: chunk[3],
         })
         .collect();
-
-    // Quantified image
-    let mut q_img = QImage::new(&quantizer, rgba_data, width as usize, height as usize, 0.)?;
-
-    // Perform quantization
-    let mut res = quantizer.quantize(&mut q_img)?;
-
-    // Palette and indexes
-    Ok(res.remapped(&mut q_img)?)
+    Ok(QImage::new(
+        quantizer,
+        rgba_data,
+        image.width() as usize,
+        image.height() as usize,
+        0.,
+    )?)
 }
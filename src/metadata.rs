@@ -0,0 +1,345 @@
+use image::codecs::gif::Repeat;
+use image::{ImageDecoder, ImageFormat, ImageResult};
+
+/// EXIF orientation tag
+const EXIF_ORIENTATION_TAG: u16 = 0x0112;
+/// EXIF horizontal resolution tag, a rational number of dots per resolution unit
+const EXIF_X_RESOLUTION_TAG: u16 = 0x011a;
+/// EXIF resolution unit tag: 2 for inches (the default) and 3 for centimeters
+const EXIF_RESOLUTION_UNIT_TAG: u16 = 0x0128;
+/// Sum of the 64 entries of the JPEG Annex K luminance quantization table, which encoders scale
+/// by the quality
+const JPEG_LUMA_TABLE_SUM: u32 = 3688;
+
+/// Ancillary metadata carried from the input image into the output
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Metadata {
+    /// ICC color profile
+    pub(crate) icc_profile: Option<Vec<u8>>,
+    /// EXIF data as a TIFF structure, with the orientation already reset
+    pub(crate) exif: Option<Vec<u8>>,
+    /// Resolution in dots per inch
+    pub(crate) dpi: Option<u16>,
+}
+
+/// Read the ICC profile and EXIF data of the input image
+/// - decoder: Decoder of the input image, before decoding the pixels
+/// - format: Input image format
+/// - bytes: Input image data
+pub(crate) fn read_metadata<D: ImageDecoder>(
+    decoder: &mut D,
+    format: ImageFormat,
+    bytes: &[u8],
+) -> ImageResult<Metadata> {
+    // The decoder converts CMYK to RGB, which a CMYK profile would then render wrong
+    let icc_profile = match format {
+        ImageFormat::Jpeg if is_cmyk_jpeg(bytes) => None,
+        _ => decoder.icc_profile()?,
+    };
+    let exif = match format {
+        // The PNG decoder of `image` doesn't expose the eXIf chunk
+        ImageFormat::Png => png_exif(bytes),
+        _ => decoder.exif_metadata()?,
+    };
+
+    // The orientation is applied to the pixels, so it must not be applied again by viewers
+    let exif = exif.map(|mut exif| {
+        reset_exif_orientation(&mut exif);
+        exif
+    });
+
+    let dpi = header_dpi(format, bytes).or_else(|| exif.as_deref().and_then(exif_dpi));
+
+    Ok(Metadata {
+        icc_profile,
+        exif,
+        dpi,
+    })
+}
+
+/// Read the resolution of the input image in dots per inch, from the pHYs chunk of a PNG, the
+/// JFIF header of a JPEG or else the EXIF resolution tags
+/// - decoder: Decoder of the input image, before decoding the pixels
+/// - format: Input image format
+/// - bytes: Input image data
+pub(crate) fn read_dpi<D: ImageDecoder>(
+    decoder: &mut D,
+    format: ImageFormat,
+    bytes: &[u8],
+) -> ImageResult<Option<u16>> {
+    if let Some(dpi) = header_dpi(format, bytes) {
+        return Ok(Some(dpi));
+    }
+    let exif = match format {
+        ImageFormat::Png => png_exif(bytes),
+        _ => decoder.exif_metadata()?,
+    };
+    Ok(exif.as_deref().and_then(exif_dpi))
+}
+
+/// Read the resolution in dots per inch from the pHYs chunk of a PNG or the JFIF header of a JPEG
+fn header_dpi(format: ImageFormat, bytes: &[u8]) -> Option<u16> {
+    let (dots, per_inch) = match format {
+        ImageFormat::Png => {
+            // Pixels per unit X (4), Y (4) and unit (1), which is 1 for meters
+            let &[a, b, c, d, _, _, _, _, 1] = png_chunk(bytes, b"pHYs")? else {
+                return None;
+            };
+            (u32::from_be_bytes([a, b, c, d]), 0.0254)
+        }
+        ImageFormat::Jpeg => {
+            // SOI, then APP0 marker (2), length (2), "JFIF\0", version (2), units (1) and
+            // X density (2), units being 1 for inches and 2 for centimeters
+            let &[0xff, 0xd8, 0xff, 0xe0, _, _, ref jfif @ .., unit, high, low] =
+                bytes.get(..16)?
+            else {
+                return None;
+            };
+            if jfif[..5] != *b"JFIF\0" {
+                return None;
+            }
+            let dots = u16::from_be_bytes([high, low]) as u32;
+            match unit {
+                1 => (dots, 1.0),
+                2 => (dots, 2.54),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    dots_per_inch(dots as f64 * per_inch)
+}
+
+/// Read the resolution in dots per inch from the first IFD of an EXIF TIFF structure
+fn exif_dpi(exif: &[u8]) -> Option<u16> {
+    let big_endian = match exif.get(..4)? {
+        [0x49, 0x49, 42, 0] => false,
+        [0x4d, 0x4d, 0, 42] => true,
+        _ => return None,
+    };
+    let read_u16 = |offset: usize| {
+        let bytes = [*exif.get(offset)?, *exif.get(offset + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let read_u32 = |offset: usize| {
+        let bytes: [u8; 4] = exif.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+
+    let ifd_offset = read_u32(4)? as usize;
+    let mut resolution = None;
+    let mut per_inch = 1.0;
+    for index in 0..read_u16(ifd_offset)? as usize {
+        // Each IFD entry is tag (2), type (2), count (4) and value or its offset (4)
+        let entry = ifd_offset + 2 + index * 12;
+        match read_u16(entry)? {
+            EXIF_X_RESOLUTION_TAG => {
+                let offset = read_u32(entry + 8)? as usize;
+                let (numerator, denominator) = (read_u32(offset)?, read_u32(offset + 4)?);
+                if denominator == 0 {
+                    return None;
+                }
+                resolution = Some(numerator as f64 / denominator as f64);
+            }
+            EXIF_RESOLUTION_UNIT_TAG => {
+                per_inch = match read_u16(entry + 8)? {
+                    2 => 1.0,
+                    3 => 2.54,
+                    _ => return None,
+                };
+            }
+            _ => {}
+        }
+    }
+    dots_per_inch(resolution? * per_inch)
+}
+
+/// Round a resolution to whole dots per inch, none when below 1
+fn dots_per_inch(dpi: f64) -> Option<u16> {
+    let dpi = dpi.round();
+    (dpi >= 1.0).then(|| dpi.min(u16::MAX as f64) as u16)
+}
+
+/// Whether a JPEG image has four color components, CMYK or YCCK as written by print tools
+pub(crate) fn is_cmyk_jpeg(bytes: &[u8]) -> bool {
+    // Skip SOI, then walk segments: marker (2), length (2), data, until the frame header, whose
+    // data is precision (1), height (2), width (2) and component count (1)
+    let mut position = 2;
+    while let Some(&[0xff, marker, high, low]) = bytes.get(position..position + 4) {
+        match marker {
+            0xc0..=0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf => {
+                return bytes.get(position + 9) == Some(&4);
+            }
+            // Start of scan, the frame header must have come before
+            0xda => return false,
+            _ => position += 2 + u16::from_be_bytes([high, low]) as usize,
+        }
+    }
+    false
+}
+
+/// Estimate the quality a JPEG image was saved with, from its luminance quantization table
+///
+/// Assumes the Annex K table scaled like libjpeg, as nearly all encoders do, and compares the sum
+/// of the table with the standard one. Returns `None` when there is no table before the image
+/// data.
+pub(crate) fn jpeg_quality(bytes: &[u8]) -> Option<u8> {
+    // Skip SOI, then walk segments: marker (2), length (2), data, until a quantization table
+    // segment, whose tables are precision and id (1) and 64 entries of 1 or 2 bytes each
+    let mut position = 2;
+    while let Some(&[0xff, marker, high, low]) = bytes.get(position..position + 4) {
+        let len = u16::from_be_bytes([high, low]) as usize;
+        match marker {
+            0xdb => {
+                let segment = bytes.get(position + 4..position + 2 + len)?;
+                let mut offset = 0;
+                while let Some(&info) = segment.get(offset) {
+                    let entry_size = if info >> 4 == 0 { 1 } else { 2 };
+                    let table = segment.get(offset + 1..offset + 1 + 64 * entry_size)?;
+                    if info & 0x0f == 0 {
+                        let sum = table
+                            .chunks_exact(entry_size)
+                            .map(|entry| entry.iter().fold(0, |sum, &byte| sum << 8 | byte as u32))
+                            .sum::<u32>();
+                        // Percent the table was scaled by, 50 being quality 100 and 100 quality 50
+                        let scale = sum as f64 * 100.0 / JPEG_LUMA_TABLE_SUM as f64;
+                        let quality = if scale <= 100.0 {
+                            (200.0 - scale) / 2.0
+                        } else {
+                            5000.0 / scale
+                        };
+                        return Some(quality.round().clamp(1.0, 100.0) as u8);
+                    }
+                    offset += 1 + 64 * entry_size;
+                }
+                position += 2 + len;
+            }
+            0xda => return None,
+            _ => position += 2 + len,
+        }
+    }
+    None
+}
+
+/// Number of palette entries of an indexed PNG image, `None` for other color types
+pub(crate) fn png_palette_size(bytes: &[u8]) -> Option<usize> {
+    // The header data is width (4), height (4), bit depth (1) and color type (1), 3 for indexed
+    if *png_chunk(bytes, b"IHDR")?.get(9)? != 3 {
+        return None;
+    }
+    Some(png_chunk(bytes, b"PLTE")?.len() / 3)
+}
+
+/// Read the eXIf chunk of a PNG image, which must come before the image data
+fn png_exif(bytes: &[u8]) -> Option<Vec<u8>> {
+    png_chunk(bytes, b"eXIf").map(<[u8]>::to_vec)
+}
+
+/// Find a chunk of a PNG image that comes before the image data
+fn png_chunk<'a>(bytes: &'a [u8], name: &[u8; 4]) -> Option<&'a [u8]> {
+    // Skip the 8 byte signature, then walk chunks: length (4), type (4), data, CRC (4)
+    let mut position = 8;
+    while let Some(header) = bytes.get(position..position + 8) {
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let data = bytes.get(position + 8..position + 8 + len)?;
+        match &header[4..8] {
+            chunk if chunk == name => return Some(data),
+            b"IDAT" => return None,
+            _ => position += 12 + len,
+        }
+    }
+    None
+}
+
+/// Read the loop count of a GIF from its NETSCAPE2.0 application extension
+///
+/// Without the extension the animation plays once, `Repeat::Finite(0)`. Only the blocks before
+/// the first image are searched, which is where encoders put it.
+pub(crate) fn gif_repeat(bytes: &[u8]) -> Repeat {
+    // Skip the 6 byte signature and the 7 byte logical screen descriptor, whose packed field
+    // tells the size of the global color table that follows
+    let Some(&packed) = bytes.get(10) else {
+        return Repeat::Finite(0);
+    };
+    let mut position = 13;
+    if packed & 0x80 != 0 {
+        position += 3 << ((packed & 0x07) + 1);
+    }
+
+    // Extensions are introducer (0x21), label and data sub-blocks: length (1), data
+    while let Some(&[0x21, label]) = bytes.get(position..position + 2) {
+        position += 2;
+        let first_block = position;
+        while let Some(&len) = bytes.get(position) {
+            position += 1 + len as usize;
+            if len == 0 {
+                break;
+            }
+        }
+        if label == 0xff
+            && let Some(&[11, ref application @ .., 3, 1, low, high]) =
+                bytes.get(first_block..first_block + 16)
+            && application == b"NETSCAPE2.0"
+        {
+            return match u16::from_le_bytes([low, high]) {
+                0 => Repeat::Infinite,
+                count => Repeat::Finite(count),
+            };
+        }
+    }
+    Repeat::Finite(0)
+}
+
+/// Set the orientation tag of an EXIF TIFF structure to 1 (no transform), if present
+pub(crate) fn reset_exif_orientation(exif: &mut [u8]) {
+    let big_endian = match exif.get(..4) {
+        Some([0x49, 0x49, 42, 0]) => false,
+        Some([0x4d, 0x4d, 0, 42]) => true,
+        _ => return,
+    };
+    let read_u16 = |exif: &[u8], offset: usize| {
+        let bytes = [*exif.get(offset)?, *exif.get(offset + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let write_u16 = |exif: &mut [u8], offset: usize, value: u16| {
+        let bytes = if big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        };
+        exif[offset..offset + 2].copy_from_slice(&bytes);
+    };
+
+    let ifd_offset = match exif.get(4..8) {
+        Some(&[a, b, c, d]) if big_endian => u32::from_be_bytes([a, b, c, d]),
+        Some(&[a, b, c, d]) => u32::from_le_bytes([a, b, c, d]),
+        _ => return,
+    } as usize;
+    let Some(entries) = read_u16(exif, ifd_offset) else {
+        return;
+    };
+
+    for index in 0..entries as usize {
+        // Each IFD entry is tag (2), type (2), count (4) and value (4)
+        let entry = ifd_offset + 2 + index * 12;
+        if entry + 12 > exif.len() {
+            return;
+        }
+        if read_u16(exif, entry) == Some(EXIF_ORIENTATION_TAG) {
+            write_u16(exif, entry + 8, 1);
+            return;
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use image::RgbImage;
+
+/// Side of the square SSIM window
+const SSIM_WINDOW: u32 = 8;
+/// Distance between two SSIM windows, so neighboring windows overlap
+const SSIM_STEP: u32 = 4;
+/// SSIM stabilizing constant of the means, (0.01 * 255)^2
+const SSIM_C1: f64 = 6.5025;
+/// SSIM stabilizing constant of the variances, (0.03 * 255)^2
+const SSIM_C2: f64 = 58.5225;
+
+/// Peak signal to noise ratio in dB over the RGB channels, infinite when the images are equal
+/// - reference: Image before compression
+/// - image: Image after compression, of the same size
+pub(crate) fn psnr(reference: &RgbImage, image: &RgbImage) -> f64 {
+    let squared_error: u64 = reference
+        .as_raw()
+        .iter()
+        .zip(image.as_raw())
+        .map(|(&a, &b)| (a as i64 - b as i64).pow(2) as u64)
+        .sum();
+    if squared_error == 0 {
+        return f64::INFINITY;
+    }
+    let mse = squared_error as f64 / reference.as_raw().len() as f64;
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+/// Mean structural similarity of the luma channel, from 0 to 1 for equal images
+/// - reference: Image before compression
+/// - image: Image after compression, of the same size
+///
+/// Windows of 8x8 pixels slide over the image in steps of 4 pixels.
+pub(crate) fn ssim(reference: &RgbImage, image: &RgbImage) -> f64 {
+    let (width, height) = reference.dimensions();
+    if width == 0 || height == 0 {
+        return 1.0;
+    }
+    let (reference, image) = (luma(reference), luma(image));
+
+    // Images smaller than a window are compared as a single window
+    let (window_width, window_height) = (SSIM_WINDOW.min(width), SSIM_WINDOW.min(height));
+    let mut total = 0.0;
+    let mut windows = 0;
+    for top in (0..=height - window_height).step_by(SSIM_STEP as usize) {
+        for left in (0..=width - window_width).step_by(SSIM_STEP as usize) {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) =
+                (0.0, 0.0, 0.0, 0.0, 0.0);
+            for y in top..top + window_height {
+                for x in left..left + window_width {
+                    let index = (y * width + x) as usize;
+                    let (a, b) = (reference[index], image[index]);
+                    sum_a += a;
+                    sum_b += b;
+                    sum_aa += a * a;
+                    sum_bb += b * b;
+                    sum_ab += a * b;
+                }
+            }
+            let count = (window_width * window_height) as f64;
+            let (mean_a, mean_b) = (sum_a / count, sum_b / count);
+            let variance_a = sum_aa / count - mean_a * mean_a;
+            let variance_b = sum_bb / count - mean_b * mean_b;
+            let covariance = sum_ab / count - mean_a * mean_b;
+
+            total += ((2.0 * mean_a * mean_b + SSIM_C1) * (2.0 * covariance + SSIM_C2))
+                / ((mean_a * mean_a + mean_b * mean_b + SSIM_C1)
+                    * (variance_a + variance_b + SSIM_C2));
+            windows += 1;
+        }
+    }
+    total / windows as f64
+}
+
+/// BT.601 luma of every pixel
+fn luma(image: &RgbImage) -> Vec<f64> {
+    image
+        .pixels()
+        .map(|pixel| 0.299 * pixel[0] as f64 + 0.587 * pixel[1] as f64 + 0.114 * pixel[2] as f64)
+        .collect()
+}
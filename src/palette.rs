@@ -0,0 +1,136 @@
+use image::RgbaImage;
+use imagequant::RGBA;
+use std::collections::{HashMap, HashSet};
+
+/// Map every pixel to the nearest palette color, by Euclidean distance in RGBA
+/// - image: Image to map
+/// - palette: Colors to map to, at most 256
+/// - dither: Spread the error of every pixel onto its neighbors (Floyd-Steinberg)
+///
+/// Returns the palette index of every pixel.
+pub(crate) fn remap_to_palette(image: &RgbaImage, palette: &[RGBA], dither: bool) -> Vec<u8> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let mut indexes = Vec::with_capacity(width * height);
+
+    if !dither {
+        indexes.extend(
+            image
+                .pixels()
+                .map(|pixel| nearest(palette, pixel.0.map(|channel| channel as f32))),
+        );
+        return indexes;
+    }
+
+    // Error carried to the current and the next row, one RGBA value per pixel
+    let mut errors = vec![[0.0f32; 4]; width];
+    let mut next_errors = vec![[0.0f32; 4]; width];
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = image.get_pixel(x as u32, y as u32).0;
+            let mut color = [0.0; 4];
+            for channel in 0..4 {
+                color[channel] = (pixel[channel] as f32 + errors[x][channel]).clamp(0.0, 255.0);
+            }
+            let index = nearest(palette, color);
+            indexes.push(index);
+
+            let RGBA { r, g, b, a } = palette[index as usize];
+            let chosen = [r, g, b, a];
+            for channel in 0..4 {
+                let error = color[channel] - chosen[channel] as f32;
+                if x + 1 < width {
+                    errors[x + 1][channel] += error * 7.0 / 16.0;
+                    next_errors[x + 1][channel] += error / 16.0;
+                }
+                if x > 0 {
+                    next_errors[x - 1][channel] += error * 3.0 / 16.0;
+                }
+                next_errors[x][channel] += error * 5.0 / 16.0;
+            }
+        }
+        std::mem::swap(&mut errors, &mut next_errors);
+        next_errors.fill([0.0; 4]);
+    }
+    indexes
+}
+
+/// Distinct colors of an image, in the order they first appear
+/// - image: Image to scan
+/// - limit: Largest number of colors to collect
+///
+/// Returns `None` as soon as there are more than `limit` colors.
+pub(crate) fn unique_colors(image: &RgbaImage, limit: usize) -> Option<Vec<RGBA>> {
+    let mut seen = HashSet::new();
+    let mut colors = Vec::new();
+    for pixel in image.pixels() {
+        if seen.insert(pixel.0) {
+            if colors.len() == limit {
+                return None;
+            }
+            let [r, g, b, a] = pixel.0;
+            colors.push(RGBA { r, g, b, a });
+        }
+    }
+    Some(colors)
+}
+
+/// Palette of exactly the colors of an image, with the palette index of every pixel
+/// - image: Image to index
+/// - limit: Largest palette size, at most 256
+///
+/// Returns `None` as soon as there are more than `limit` colors, so the image needs
+/// quantization instead.
+pub(crate) fn exact_palette(image: &RgbaImage, limit: usize) -> Option<(Vec<RGBA>, Vec<u8>)> {
+    let (palette, mut indexes) = exact_shared_palette(&[image], limit)?;
+    Some((palette, indexes.remove(0)))
+}
+
+/// Palette of exactly the colors of several images, with the palette indexes of each image
+/// - images: Images to index with one palette
+/// - limit: Largest palette size, at most 256
+///
+/// Returns `None` as soon as the images have more than `limit` colors together.
+pub(crate) fn exact_shared_palette(
+    images: &[&RgbaImage],
+    limit: usize,
+) -> Option<(Vec<RGBA>, Vec<Vec<u8>>)> {
+    let mut palette = Vec::new();
+    let mut lookup = HashMap::new();
+    let mut all_indexes = Vec::with_capacity(images.len());
+    for image in images {
+        let mut indexes = Vec::with_capacity(image.width() as usize * image.height() as usize);
+        for pixel in image.pixels() {
+            let index = match lookup.get(&pixel.0) {
+                Some(&index) => index,
+                None => {
+                    if palette.len() == limit {
+                        return None;
+                    }
+                    let index = palette.len() as u8;
+                    let [r, g, b, a] = pixel.0;
+                    palette.push(RGBA { r, g, b, a });
+                    lookup.insert(pixel.0, index);
+                    index
+                }
+            };
+            indexes.push(index);
+        }
+        all_indexes.push(indexes);
+    }
+    Some((palette, all_indexes))
+}
+
+/// Index of the palette color nearest to a color
+fn nearest(palette: &[RGBA], color: [f32; 4]) -> u8 {
+    let distance = |entry: &RGBA| {
+        let entry = [entry.r, entry.g, entry.b, entry.a];
+        (0..4)
+            .map(|channel| (entry[channel] as f32 - color[channel]).powi(2))
+            .sum::<f32>()
+    };
+    palette
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| distance(a).total_cmp(&distance(b)))
+        .map_or(0, |(index, _)| index as u8)
+}
@@ -0,0 +1,102 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage};
+
+/// Largest width and height of the image the saliency is computed on
+const ANALYSIS_MAX_SIZE: u32 = 256;
+
+/// Find the crop window with the most detail, measured as the luma gradient it covers
+/// - image: Image to crop
+/// - crop_width: Width of the window, at most the image width
+/// - crop_height: Height of the window, at most the image height
+///
+/// Returns the left and top of the window. The saliency is computed on a copy downsampled to at
+/// most 256x256 pixels, and among equally scored windows the one closest to the center wins, so
+/// flat images get a center crop.
+pub(crate) fn find_crop(image: &DynamicImage, crop_width: u32, crop_height: u32) -> (u32, u32) {
+    let (width, height) = (image.width(), image.height());
+    let (free_x, free_y) = (width - crop_width, height - crop_height);
+    if free_x == 0 && free_y == 0 {
+        return (0, 0);
+    }
+
+    let scale = (ANALYSIS_MAX_SIZE as f64 / width.max(height) as f64).min(1.0);
+    let scaled = |value: u32| ((value as f64 * scale).round() as u32).max(1);
+    let luma = image
+        .resize_exact(scaled(width), scaled(height), FilterType::Triangle)
+        .into_luma8();
+    let (window_width, window_height) = (
+        scaled(crop_width).min(luma.width()),
+        scaled(crop_height).min(luma.height()),
+    );
+    let table = summed_area_table(&gradient(&luma), luma.width(), luma.height());
+
+    let (max_left, max_top) = (luma.width() - window_width, luma.height() - window_height);
+    let center = (max_left as f64 / 2.0, max_top as f64 / 2.0);
+    let mut best = (0, 0);
+    let mut best_key = (0, f64::NEG_INFINITY);
+    for top in 0..=max_top {
+        for left in 0..=max_left {
+            let score = table.sum(left, top, window_width, window_height);
+            let distance = (left as f64 - center.0).hypot(top as f64 - center.1);
+            if score > best_key.0 || (score == best_key.0 && -distance > best_key.1) {
+                best = (left, top);
+                best_key = (score, -distance);
+            }
+        }
+    }
+
+    // Back to full size, clamped as rounding may push the window past the edge
+    (
+        ((best.0 as f64 / scale).round() as u32).min(free_x),
+        ((best.1 as f64 / scale).round() as u32).min(free_y),
+    )
+}
+
+/// Gradient magnitude of every pixel, the sum of the absolute central differences
+fn gradient(luma: &GrayImage) -> Vec<u32> {
+    let (width, height) = luma.dimensions();
+    let value = |x: u32, y: u32| luma.get_pixel(x, y).0[0] as i32;
+    let mut magnitudes = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            // Edges use the pixel itself in place of the missing neighbor
+            let dx = value((x + 1).min(width - 1), y) - value(x.saturating_sub(1), y);
+            let dy = value(x, (y + 1).min(height - 1)) - value(x, y.saturating_sub(1));
+            magnitudes.push(dx.unsigned_abs() + dy.unsigned_abs());
+        }
+    }
+    magnitudes
+}
+
+/// Sums over every rectangle of a grid of values in constant time
+struct SummedAreaTable {
+    /// Sum of the values above and left of every point, with an extra row and column of zeros
+    sums: Vec<u64>,
+    width: u32,
+}
+
+/// Build the summed area table of a grid of values
+fn summed_area_table(values: &[u32], width: u32, height: u32) -> SummedAreaTable {
+    let stride = (width + 1) as usize;
+    let mut sums = vec![0u64; stride * (height + 1) as usize];
+    for y in 0..height as usize {
+        let mut row_sum = 0;
+        for x in 0..width as usize {
+            row_sum += values[y * width as usize + x] as u64;
+            sums[(y + 1) * stride + x + 1] = sums[y * stride + x + 1] + row_sum;
+        }
+    }
+    SummedAreaTable { sums, width }
+}
+
+impl SummedAreaTable {
+    /// Sum of the values inside a rectangle
+    fn sum(&self, left: u32, top: u32, width: u32, height: u32) -> u64 {
+        let stride = (self.width + 1) as usize;
+        let (left, top) = (left as usize, top as usize);
+        let (right, bottom) = (left + width as usize, top + height as usize);
+        self.sums[bottom * stride + right] + self.sums[top * stride + left]
+            - self.sums[top * stride + right]
+            - self.sums[bottom * stride + left]
+    }
+}
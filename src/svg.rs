@@ -0,0 +1,69 @@
+use super::error::{Error, ErrorCode};
+use image::RgbaImage;
+use resvg::{tiny_skia, usvg};
+
+/// Number of bytes at the start of the input searched for the `<svg` tag
+const SNIFF_LENGTH: usize = 1024;
+
+/// Whether the input looks like an SVG document
+///
+/// `image::guess_format` has no signature for SVG, which is plain XML, so this checks for a
+/// document starting with a tag, after an optional byte order mark and whitespace, that opens an
+/// `<svg` element early on. XML declarations, comments and doctypes may come first.
+pub(crate) fn is_svg(bytes: &[u8]) -> bool {
+    let bytes = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes);
+    let head = bytes[..bytes.len().min(SNIFF_LENGTH)].trim_ascii_start();
+    head.starts_with(b"<") && head.windows(4).any(|window| window == b"<svg")
+}
+
+/// Rasterize an SVG document
+/// - bytes: SVG document
+/// - width: Width to render at (0 for auto from the aspect ratio)
+/// - height: Height to render at (0 for auto from the aspect ratio)
+/// - max_pixels: Largest width times height to render, 0 for no limit
+///
+/// Without a width or height the document renders at its own size. Otherwise it is scaled
+/// uniformly to fit inside the given size, like `ResizeMode::Fit`. Text and embedded raster
+/// images aren't rendered, as no fonts or image decoders are bundled.
+pub(crate) fn rasterize(
+    bytes: &[u8],
+    width: u32,
+    height: u32,
+    max_pixels: u32,
+) -> Result<RgbaImage, Error> {
+    let tree = usvg::Tree::from_data(bytes, &usvg::Options::default())
+        .map_err(|error| Error::new(ErrorCode::DecodeFailed, error.to_string()))?;
+    let size = tree.size();
+    let scale = match (width, height) {
+        (0, 0) => 1.0,
+        (width, 0) => width as f32 / size.width(),
+        (0, height) => height as f32 / size.height(),
+        (width, height) => (width as f32 / size.width()).min(height as f32 / size.height()),
+    };
+    let (width, height) = (
+        ((size.width() * scale).round() as u32).max(1),
+        ((size.height() * scale).round() as u32).max(1),
+    );
+    if max_pixels > 0 && width as u64 * height as u64 > max_pixels as u64 {
+        return Err(Error::new(
+            ErrorCode::ImageTooLarge,
+            format!("Image of {width}x{height} pixels exceeds max_pixels {max_pixels}"),
+        ));
+    }
+
+    let mut pixmap = tiny_skia::Pixmap::new(width, height).ok_or_else(|| {
+        Error::new(
+            ErrorCode::ImageTooLarge,
+            format!("Can't render an image of {width}x{height} pixels"),
+        )
+    })?;
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+    Ok(
+        RgbaImage::from_raw(width, height, pixmap.take_demultiplied())
+            .expect("Failed to create image"),
+    )
+}
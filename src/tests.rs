@@ -0,0 +1,1118 @@
+use super::*;
+use image::{GenericImageView, Rgb, RgbaImage};
+
+/// Encode an image in a format of the `image` crate
+fn encode(image: impl Into<DynamicImage>, format: ImageFormat) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    image
+        .into()
+        .write_to(&mut Cursor::new(&mut bytes), format)
+        .expect("Failed to encode test image");
+    bytes
+}
+
+/// Opaque image with smooth gradients and some detail, like a small photo
+fn photo(width: u32, height: u32) -> RgbImage {
+    RgbImage::from_fn(width, height, |x, y| {
+        let detail = ((x * 7 + y * 13) % 17) as u8;
+        Rgb([
+            (x * 2) as u8 ^ detail,
+            (y * 3) as u8,
+            ((x + y) as u8).wrapping_add(detail),
+        ])
+    })
+}
+
+/// Options that always re-encode, so the output is never the input
+fn forced() -> CompressOptions {
+    CompressOptions {
+        force: true,
+        ..CompressOptions::default()
+    }
+}
+
+#[test]
+fn optimized_huffman_tables_shrink_jpeg_and_decode() {
+    let input = encode(photo(200, 150), ImageFormat::Jpeg);
+    let compress_with = |progressive: bool, optimize_huffman: bool| {
+        let options = CompressOptions {
+            progressive,
+            optimize_huffman,
+            ..forced()
+        };
+        let result = compress_detailed(&input, &options).expect("Failed to compress");
+        let decoded = image::load_from_memory(&result.bytes).expect("Failed to decode output");
+        (result.bytes.len(), decoded.into_rgb8())
+    };
+
+    for progressive in [true, false] {
+        let (plain_size, plain) = compress_with(progressive, false);
+        let (optimized_size, optimized) = compress_with(progressive, true);
+        assert!(
+            optimized_size < plain_size,
+            "progressive {progressive}: {optimized_size} >= {plain_size}"
+        );
+        // The tables only change the entropy coding, not the coefficients
+        assert_eq!(plain, optimized);
+        assert_eq!(optimized.dimensions(), (200, 150));
+    }
+
+    let options = CompressOptions {
+        measure_quality: true,
+        ..forced()
+    };
+    let result = compress_detailed(&input, &options).expect("Failed to measure quality");
+    // Measuring decodes the output again, which failed while baseline output had fitted tables
+    assert!(result.psnr.is_some_and(|psnr| psnr > 20.0));
+    assert!(result.ssim.is_some_and(|ssim| ssim > 0.9));
+
+    let result = compress_to_quality(&input, 0.95, 1.0).expect("Failed to search quality");
+    assert!(result.ssim.is_some_and(|ssim| ssim >= 0.95));
+    assert!(result.warnings.is_empty());
+}
+
+/// Type and data of every chunk of a PNG, in file order
+fn png_chunks(bytes: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut chunks = Vec::new();
+    // Skip the 8 byte signature, each chunk is length (4), type (4), data and CRC (4)
+    let mut position = 8;
+    while let Some(header) = bytes.get(position..position + 8) {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let name = [header[4], header[5], header[6], header[7]];
+        chunks.push((name, &bytes[position + 8..position + 8 + length]));
+        position += 12 + length;
+    }
+    chunks
+}
+
+/// Bit depth and color type of a PNG, from its IHDR chunk
+fn png_format(bytes: &[u8]) -> (u8, u8) {
+    let (name, ihdr) = png_chunks(bytes)[0];
+    assert_eq!(&name, b"IHDR");
+    (ihdr[8], ihdr[9])
+}
+
+/// Frame of a test GIF: pixels, offset, delay in centiseconds and disposal
+struct GifFrame {
+    image: RgbaImage,
+    left: u16,
+    top: u16,
+    delay: u16,
+    dispose: gif::DisposalMethod,
+}
+
+/// Encode a looping GIF, each frame with its own palette
+fn encode_gif(width: u16, height: u16, frames: Vec<GifFrame>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut encoder = gif::Encoder::new(&mut bytes, width, height, &[]).expect("Failed to encode");
+    encoder
+        .set_repeat(gif::Repeat::Infinite)
+        .expect("Failed to encode");
+    for frame in frames {
+        let (frame_width, frame_height) = frame.image.dimensions();
+        let mut pixels = frame.image.into_raw();
+        let mut gif_frame =
+            gif::Frame::from_rgba(frame_width as u16, frame_height as u16, &mut pixels);
+        gif_frame.left = frame.left;
+        gif_frame.top = frame.top;
+        gif_frame.delay = frame.delay;
+        gif_frame.dispose = frame.dispose;
+        encoder.write_frame(&gif_frame).expect("Failed to encode");
+    }
+    drop(encoder);
+    bytes
+}
+
+/// Read the header of every frame of a GIF, without the pixels
+fn gif_frame_headers(bytes: &[u8]) -> Vec<gif::Frame<'static>> {
+    let mut decoder = gif::DecodeOptions::new()
+        .read_info(bytes)
+        .expect("Failed to decode GIF");
+    let mut headers = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().expect("Failed to decode GIF") {
+        headers.push(gif::Frame {
+            buffer: Cow::Borrowed(&[]),
+            palette: None,
+            ..frame.clone()
+        });
+    }
+    headers
+}
+
+/// Encode a sequential JPEG with jpeg-encoder, interleaving the components in one scan
+fn encode_jpeg_with(image: &DynamicImage, sampling: jpeg_encoder::SamplingFactor) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut bytes, 90);
+    encoder.set_sampling_factor(sampling);
+    let (data, color_type) = match image {
+        DynamicImage::ImageLuma8(gray) => (gray.as_raw(), jpeg_encoder::ColorType::Luma),
+        DynamicImage::ImageRgb8(rgb) => (rgb.as_raw(), jpeg_encoder::ColorType::Rgb),
+        _ => unreachable!("Test images are gray or RGB"),
+    };
+    encoder
+        .encode(
+            data,
+            image.width() as u16,
+            image.height() as u16,
+            color_type,
+        )
+        .expect("Failed to encode test JPEG");
+    bytes
+}
+
+/// EXIF TIFF structure, little endian, with one IFD of entries whose values fit in 4 bytes
+/// - entries: Tag, type, count and value of every entry, sorted by tag
+fn exif_tiff(entries: &[(u16, u16, u32, u32)]) -> Vec<u8> {
+    let mut exif = b"II*\0\x08\0\0\0".to_vec();
+    exif.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    for &(tag, kind, count, value) in entries {
+        exif.extend_from_slice(&tag.to_le_bytes());
+        exif.extend_from_slice(&kind.to_le_bytes());
+        exif.extend_from_slice(&count.to_le_bytes());
+        exif.extend_from_slice(&value.to_le_bytes());
+    }
+    // No next IFD
+    exif.extend_from_slice(&[0; 4]);
+    exif
+}
+
+/// Insert EXIF data into a JPEG as an APP1 segment right after the start of image
+fn with_exif(jpeg: &[u8], exif: &[u8]) -> Vec<u8> {
+    let mut app1 = b"Exif\0\0".to_vec();
+    app1.extend_from_slice(exif);
+    let mut bytes = jpeg[..2].to_vec();
+    bytes.extend_from_slice(&[0xff, 0xe1]);
+    bytes.extend_from_slice(&(app1.len() as u16 + 2).to_be_bytes());
+    bytes.extend_from_slice(&app1);
+    bytes.extend_from_slice(&jpeg[2..]);
+    bytes
+}
+
+/// Largest difference of any channel between two images of the same size
+fn max_difference(a: &DynamicImage, b: &DynamicImage) -> u8 {
+    assert_eq!(a.dimensions(), b.dimensions());
+    let (a, b) = (a.to_rgb8(), b.to_rgb8());
+    a.as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(a, b)| a.abs_diff(*b))
+        .max()
+        .unwrap_or(0)
+}
+
+#[test]
+fn lossless_jpeg_transforms_match_decoded_transforms() {
+    use jpeg_encoder::SamplingFactor;
+
+    let rgb = DynamicImage::ImageRgb8(photo(64, 48));
+    let gray = DynamicImage::ImageLuma8(rgb.to_luma8());
+    // The decoder upsamples subsampled chroma with a slight offset, which moves with the blocks
+    let inputs = [
+        ("4:4:4", &rgb, SamplingFactor::R_4_4_4, 3),
+        ("4:2:2", &rgb, SamplingFactor::R_4_2_2, 8),
+        ("4:2:0", &rgb, SamplingFactor::R_4_2_0, 8),
+        ("gray", &gray, SamplingFactor::R_4_4_4, 1),
+    ];
+    let transforms = [
+        Transform::Rotate90,
+        Transform::Rotate180,
+        Transform::Rotate270,
+        Transform::FlipH,
+        Transform::FlipV,
+    ];
+    for (name, image, sampling, tolerance) in inputs {
+        let input = encode_jpeg_with(image, sampling);
+        let decoded = image::load_from_memory(&input).expect("Failed to decode input");
+        for transform in transforms {
+            let output = jpeg_transform::transform(&input, transform, 0)
+                .unwrap_or_else(|error| panic!("{name} {transform:?}: {}", error.message()));
+            let transformed = image::load_from_memory(&output).expect("Failed to decode output");
+            let difference = max_difference(&transformed, &transform.apply(decoded.clone()));
+            assert!(
+                difference <= tolerance,
+                "{name} {transform:?} differs by {difference}"
+            );
+        }
+
+        // Four quarter turns move every coefficient back to where it was
+        let mut output = input.clone();
+        for _ in 0..4 {
+            output = rotate_jpeg_lossless(&output, 90)
+                .expect("Failed to rotate")
+                .bytes;
+        }
+        let turned = image::load_from_memory(&output).expect("Failed to decode output");
+        assert_eq!(max_difference(&turned, &decoded), 0, "{name}");
+    }
+}
+
+#[test]
+fn lossless_jpeg_rotation_returns_unsupported_input_with_warning() {
+    let partial_blocks = encode_jpeg_with(
+        &DynamicImage::ImageRgb8(photo(65, 50)),
+        jpeg_encoder::SamplingFactor::R_4_2_0,
+    );
+    let mut progressive = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut progressive, 90);
+    encoder.set_progressive(true);
+    encoder
+        .encode(photo(64, 48).as_raw(), 64, 48, jpeg_encoder::ColorType::Rgb)
+        .expect("Failed to encode test JPEG");
+
+    for input in [partial_blocks, progressive] {
+        let result = rotate_jpeg_lossless(&input, 180).expect("Failed to rotate");
+        assert!(!result.rotated);
+        assert!(result.warning.is_some());
+        assert_eq!(result.bytes, input);
+    }
+}
+
+#[test]
+fn lossless_jpeg_rotation_resets_exif_orientation() {
+    // Orientation 6, the stored pixels are rotated 90 degrees clockwise to display
+    let jpeg = encode_jpeg_with(
+        &DynamicImage::ImageRgb8(photo(64, 48)),
+        jpeg_encoder::SamplingFactor::R_4_4_4,
+    );
+    let input = with_exif(&jpeg, &exif_tiff(&[(0x0112, 3, 1, 6)]));
+
+    let orientation = |bytes: &[u8]| {
+        let mut decoder =
+            image::codecs::jpeg::JpegDecoder::new(Cursor::new(bytes)).expect("Failed to decode");
+        decoder.orientation().expect("Failed to read orientation")
+    };
+    assert_eq!(orientation(&input), image::metadata::Orientation::Rotate90);
+    let result = rotate_jpeg_lossless(&input, 90).expect("Failed to rotate");
+    assert!(result.rotated);
+    assert_eq!(
+        orientation(&result.bytes),
+        image::metadata::Orientation::NoTransforms
+    );
+}
+
+#[test]
+fn gif_delta_frames_keep_delays_and_offsets() {
+    let first = RgbaImage::from_fn(32, 24, |x, y| {
+        Rgba([(x * 8) as u8, (y / 4 * 40) as u8, 40, 255])
+    });
+    // At most 256 colors, so the frames are written without loss. The second frame changes a
+    // 4x6 area at (10, 12), the third changes nothing
+    let mut second = first.clone();
+    for y in 12..18 {
+        for x in 10..14 {
+            second.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+        }
+    }
+    let frames = [(first, 5), (second.clone(), 20), (second, 70)]
+        .into_iter()
+        .map(|(image, delay)| GifFrame {
+            image,
+            left: 0,
+            top: 0,
+            delay,
+            dispose: gif::DisposalMethod::Keep,
+        })
+        .collect();
+    let input = encode_gif(32, 24, frames);
+
+    let options = CompressOptions {
+        quality: 100,
+        gif_delta_frames: true,
+        ..forced()
+    };
+    let result = compress_detailed(&input, &options).expect("Failed to compress");
+    let headers = gif_frame_headers(&result.bytes);
+    let layout = headers
+        .iter()
+        .map(|frame| {
+            (
+                frame.delay,
+                frame.left,
+                frame.top,
+                frame.width,
+                frame.height,
+                frame.dispose,
+            )
+        })
+        .collect::<Vec<_>>();
+    let keep = gif::DisposalMethod::Keep;
+    assert_eq!(
+        layout,
+        [
+            (5, 0, 0, 32, 24, keep),
+            (20, 10, 12, 4, 6, keep),
+            (70, 0, 0, 1, 1, keep),
+        ]
+    );
+}
+
+#[test]
+fn exif_orientations_are_applied_to_the_pixels() {
+    // 3x2 blocks of 8x8 pixels, each a flat color, so JPEG keeps them apart
+    const COLORS: [[u8; 3]; 6] = [
+        [255, 0, 0],
+        [0, 255, 0],
+        [0, 0, 255],
+        [255, 255, 0],
+        [255, 255, 255],
+        [0, 0, 0],
+    ];
+    let (width, height) = (24, 16);
+    let color = |x: u32, y: u32| COLORS[(y / 8 * 3 + x / 8) as usize];
+    let jpeg = encode_jpeg_with(
+        &DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| Rgb(color(x, y)))),
+        jpeg_encoder::SamplingFactor::R_4_4_4,
+    );
+
+    for orientation in 1..=8 {
+        let input = with_exif(&jpeg, &exif_tiff(&[(0x0112, 3, 1, orientation)]));
+        let source = decode_source(&input, true, 0).expect("Failed to decode");
+        let image = source.image.to_rgb8();
+        // Position in the stored image of every displayed pixel
+        let stored = |x: u32, y: u32| match orientation {
+            1 => (x, y),
+            2 => (width - 1 - x, y),
+            3 => (width - 1 - x, height - 1 - y),
+            4 => (x, height - 1 - y),
+            5 => (y, x),
+            6 => (y, height - 1 - x),
+            7 => (width - 1 - y, height - 1 - x),
+            _ => (width - 1 - y, x),
+        };
+        let expected_size = if orientation >= 5 {
+            (height, width)
+        } else {
+            (width, height)
+        };
+        assert_eq!(
+            image.dimensions(),
+            expected_size,
+            "orientation {orientation}"
+        );
+
+        // The centers of the blocks, away from the ringing at their edges
+        for y in (4..image.height()).step_by(8) {
+            for x in (4..image.width()).step_by(8) {
+                let (stored_x, stored_y) = stored(x, y);
+                let expected = color(stored_x, stored_y);
+                let actual = image.get_pixel(x, y).0;
+                assert!(
+                    actual
+                        .iter()
+                        .zip(expected)
+                        .all(|(actual, expected)| actual.abs_diff(expected) <= 8),
+                    "orientation {orientation} at ({x}, {y}): {actual:?} != {expected:?}"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn gps_tags_are_only_kept_without_strip_metadata() {
+    // IFD0 at offset 8 holds one entry, so the GPS IFD follows at 8 + 2 + 12 + 4
+    let mut exif = exif_tiff(&[(0x8825, 4, 1, 26)]);
+    // GPS IFD with the latitude reference "N"
+    exif.extend_from_slice(&1u16.to_le_bytes());
+    exif.extend_from_slice(&[0x01, 0x00, 2, 0, 2, 0, 0, 0, b'N', 0, 0, 0, 0, 0, 0, 0]);
+    let jpeg = encode_jpeg_with(
+        &DynamicImage::ImageRgb8(photo(32, 32)),
+        jpeg_encoder::SamplingFactor::R_4_2_0,
+    );
+    let input = with_exif(&jpeg, &exif);
+
+    let output_exif = |strip_metadata: bool| {
+        let options = CompressOptions {
+            strip_metadata,
+            ..forced()
+        };
+        let result = compress_detailed(&input, &options).expect("Failed to compress");
+        let mut decoder = image::codecs::jpeg::JpegDecoder::new(Cursor::new(&result.bytes))
+            .expect("Failed to decode output");
+        decoder.exif_metadata().expect("Failed to read EXIF")
+    };
+    assert_eq!(output_exif(true), None);
+    assert_eq!(output_exif(false), Some(exif));
+}
+
+#[test]
+fn progressive_jpeg_decodes_with_the_same_dimensions() {
+    // Sizes that aren't whole blocks of any subsampling
+    let input = encode(photo(75, 41), ImageFormat::Jpeg);
+    for chroma_subsampling in [
+        ChromaSubsampling::Yuv444,
+        ChromaSubsampling::Yuv422,
+        ChromaSubsampling::Yuv420,
+    ] {
+        let options = CompressOptions {
+            progressive: true,
+            chroma_subsampling,
+            ..forced()
+        };
+        let result = compress_detailed(&input, &options).expect("Failed to compress");
+        // A progressive frame starts with SOF2
+        assert!(result.bytes.windows(2).any(|marker| marker == [0xff, 0xc2]));
+        let decoded = image::load_from_memory(&result.bytes).expect("Failed to decode output");
+        assert_eq!(decoded.dimensions(), (75, 41));
+    }
+}
+
+#[test]
+fn qoi_output_decodes_to_the_same_pixels() {
+    let rgb = photo(37, 23);
+    let rgba = RgbaImage::from_fn(37, 23, |x, y| {
+        let [r, g, b] = rgb.get_pixel(x, y).0;
+        Rgba([r, g, b, (x * 7 + y) as u8])
+    });
+    for image in [DynamicImage::ImageRgb8(rgb), DynamicImage::ImageRgba8(rgba)] {
+        let output = transcode(
+            &encode(image.clone(), ImageFormat::Png),
+            "qoi",
+            75,
+            1.0,
+            None,
+        )
+        .expect("Failed to transcode");
+        assert_eq!(image::guess_format(&output).ok(), Some(ImageFormat::Qoi));
+        let decoded = image::load_from_memory(&output).expect("Failed to decode output");
+        assert_eq!(decoded.color(), image.color());
+        assert_eq!(decoded.as_bytes(), image.as_bytes());
+
+        // QOI input stays QOI and keeps its pixels
+        let result = compress_detailed(&output, &forced()).expect("Failed to compress");
+        let decoded = image::load_from_memory(&result.bytes).expect("Failed to decode output");
+        assert_eq!(decoded.as_bytes(), image.as_bytes());
+    }
+}
+
+#[test]
+fn low_bit_depth_png_round_trips() {
+    // Colors of 1, 2 and 4 bit palettes and gray levels of 1, 2 and 4 bits. The widths leave
+    // the last byte of every row partly filled.
+    let palette = |colors: u32| -> Vec<Rgba<u8>> {
+        (0..colors)
+            .map(|index| Rgba([(index * 16) as u8, 200 - index as u8 * 10, 90, 255]))
+            .collect()
+    };
+    let gray = |depth: u32| -> Vec<Rgba<u8>> {
+        let step = 255 / ((1 << depth) - 1);
+        (0..1 << depth)
+            .map(|level| {
+                let value = (level * step) as u8;
+                Rgba([value, value, value, 255])
+            })
+            .collect()
+    };
+    // Colors, width, expected bit depth and color type (3 indexed, 0 gray)
+    let cases = [
+        (palette(2), 13, 1, 3),
+        (palette(4), 9, 2, 3),
+        (palette(16), 7, 4, 3),
+        (gray(1), 11, 1, 0),
+        (gray(2), 5, 2, 0),
+        (gray(4), 3, 4, 0),
+    ];
+    for (colors, width, depth, color_type) in cases {
+        let image = RgbaImage::from_fn(width, 6, |x, y| {
+            colors[((x + y * 3) % colors.len() as u32) as usize]
+        });
+        let options = CompressOptions {
+            quality: 100,
+            ..forced()
+        };
+        let input = encode(image.clone(), ImageFormat::Png);
+        let result = compress_detailed(&input, &options).expect("Failed to compress");
+        assert_eq!(
+            png_format(&result.bytes),
+            (depth, color_type),
+            "{} colors, {width} wide",
+            colors.len()
+        );
+        let decoded = image::load_from_memory(&result.bytes).expect("Failed to decode output");
+        assert_eq!(
+            decoded.to_rgba8(),
+            image,
+            "{} colors, {width} wide",
+            colors.len()
+        );
+    }
+}
+
+#[test]
+fn png_trns_chunk_leaves_out_opaque_entries() {
+    let colors = [
+        Rgba([200, 30, 30, 255]),
+        Rgba([30, 200, 30, 255]),
+        Rgba([30, 30, 200, 255]),
+        Rgba([0, 0, 0, 0]),
+        Rgba([90, 90, 10, 128]),
+    ];
+    let trns = |opaque_colors: usize, transparent_colors: usize| {
+        let colors = [&colors[..opaque_colors], &colors[3..3 + transparent_colors]].concat();
+        let image = RgbaImage::from_fn(10, 10, |x, y| colors[((x + y) as usize) % colors.len()]);
+        let options = CompressOptions {
+            quality: 100,
+            ..forced()
+        };
+        let input = encode(image.clone(), ImageFormat::Png);
+        let result = compress_detailed(&input, &options).expect("Failed to compress");
+        let decoded = image::load_from_memory(&result.bytes).expect("Failed to decode output");
+        assert_eq!(decoded.to_rgba8(), image);
+        assert_eq!(png_format(&result.bytes).1, 3, "Not an indexed PNG");
+        png_chunks(&result.bytes)
+            .into_iter()
+            .find(|(name, _)| name == b"tRNS")
+            .map(|(_, data)| data.to_vec())
+    };
+
+    assert_eq!(trns(3, 0), None);
+    // Transparent entries come first, so the opaque ones after them need no alpha value
+    assert_eq!(trns(3, 1), Some(vec![0]));
+    let mut alpha = trns(3, 2).expect("Missing tRNS chunk");
+    alpha.sort();
+    assert_eq!(alpha, [0, 128]);
+}
+
+#[test]
+fn blurhash_matches_reference_implementation() {
+    // Expected hashes from the reference implementation at https://github.com/woltapp/blurhash
+    let image = RgbImage::from_fn(20, 12, |x, y| {
+        let blue = if (x / 5 + y / 4) % 2 == 1 { 255 } else { 40 };
+        Rgb([(x * 255 / 19) as u8, (y * 255 / 11) as u8, blue])
+    });
+    assert_eq!(blurhash::encode(&image, 1, 1), "00HoOu");
+    assert_eq!(
+        blurhash::encode(&image, 4, 3),
+        "L$HoOu2-wxbnqTR-jte=g0fefQfc"
+    );
+}
+
+#[test]
+fn frames_quantize_the_same_in_batches_and_alone() {
+    // With the `parallel` feature the batch is quantized on several threads at once
+    let photo = photo(40, 30);
+    let frames = (0..6)
+        .map(|index| {
+            let image = RgbaImage::from_fn(40, 30, |x, y| {
+                let [r, g, b] = photo.get_pixel((x + index * 5) % 40, y).0;
+                Rgba([r, g, b, if x < index { 0 } else { 255 }])
+            });
+            Frame::new(image)
+        })
+        .collect::<Vec<_>>();
+    let options = CompressOptions {
+        quality: 60,
+        ..forced()
+    };
+
+    let batched = quantize_frames(&frames, &options).expect("Failed to quantize");
+    for (frame, (batched, batched_quality)) in frames.iter().zip(batched) {
+        let (alone, alone_quality) = quantize_frames(std::slice::from_ref(frame), &options)
+            .expect("Failed to quantize")
+            .remove(0);
+        assert_eq!(batched.buffer(), alone.buffer());
+        assert_eq!(batched_quality, alone_quality);
+    }
+}
+
+#[test]
+#[ignore = "timing, compare runs with and without the `parallel` feature"]
+fn quantize_frames_timing() {
+    let photo = photo(320, 240);
+    let frames = (0..24)
+        .map(|index| {
+            let image = RgbaImage::from_fn(320, 240, |x, y| {
+                let [r, g, b] = photo.get_pixel((x + index * 13) % 320, y).0;
+                Rgba([r, g, b, 255])
+            });
+            Frame::new(image)
+        })
+        .collect::<Vec<_>>();
+    let options = CompressOptions {
+        quality: 60,
+        ..forced()
+    };
+
+    let start = std::time::Instant::now();
+    let quantized = quantize_frames(&frames, &options).expect("Failed to quantize");
+    let elapsed = start.elapsed();
+    assert_eq!(quantized.len(), frames.len());
+    println!(
+        "Quantized {} frames of 320x240 in {elapsed:?}, parallel: {}, cores: {}",
+        frames.len(),
+        cfg!(feature = "parallel"),
+        std::thread::available_parallelism().map_or(1, |cores| cores.get())
+    );
+}
+
+#[test]
+fn interlaced_png_decodes_to_the_same_pixels() {
+    // Sizes that leave some of the seven passes empty, and depths that pack several pixels
+    // into a byte
+    let sizes = [(1, 1), (2, 3), (5, 4), (7, 7), (8, 8), (9, 10), (17, 3)];
+    let palettes: [&[Rgba<u8>]; 3] = [
+        &[Rgba([0, 0, 0, 255]), Rgba([255, 255, 255, 255])],
+        &[
+            Rgba([200, 30, 30, 255]),
+            Rgba([30, 200, 30, 255]),
+            Rgba([30, 30, 200, 255]),
+            Rgba([0, 0, 0, 0]),
+        ],
+        &[
+            Rgba([200, 30, 30, 255]),
+            Rgba([30, 200, 30, 255]),
+            Rgba([30, 30, 200, 255]),
+            Rgba([0, 0, 0, 0]),
+            Rgba([90, 90, 10, 128]),
+            Rgba([10, 90, 90, 255]),
+        ],
+    ];
+    let options = CompressOptions {
+        quality: 100,
+        interlace: true,
+        ..forced()
+    };
+    for (width, height) in sizes {
+        for palette in palettes {
+            let image = RgbaImage::from_fn(width, height, |x, y| {
+                palette[((x * 3 + y * 5) as usize) % palette.len()]
+            });
+            let input = encode(image.clone(), ImageFormat::Png);
+            let result = compress_detailed(&input, &options).expect("Failed to compress");
+            let (name, ihdr) = png_chunks(&result.bytes)[0];
+            assert_eq!(
+                (&name, ihdr[12]),
+                (b"IHDR", 1),
+                "{width}x{height} not interlaced"
+            );
+            let decoded = image::load_from_memory(&result.bytes).expect("Failed to decode output");
+            assert_eq!(
+                decoded.to_rgba8(),
+                image,
+                "{width}x{height} with {} colors",
+                palette.len()
+            );
+        }
+    }
+}
+
+#[test]
+fn dpi_is_written_and_read_back() {
+    for format in [ImageFormat::Png, ImageFormat::Jpeg] {
+        let input = encode(photo(16, 16), format);
+        let options = CompressOptions {
+            dpi: Some(300),
+            ..forced()
+        };
+        let output = compress_detailed(&input, &options)
+            .expect("Failed to compress")
+            .bytes;
+        match format {
+            ImageFormat::Png => {
+                let (_, phys) = png_chunks(&output)
+                    .into_iter()
+                    .find(|(name, _)| name == b"pHYs")
+                    .expect("Missing pHYs chunk");
+                // 300 dots per inch are 11811 per meter, in both directions
+                let mut expected = [11811u32.to_be_bytes(), 11811u32.to_be_bytes()].concat();
+                expected.push(1);
+                assert_eq!(phys, expected);
+            }
+            _ => {
+                // JFIF APP0 with units 1 (inches) and the density in both directions
+                assert_eq!(&output[6..11], b"JFIF\0");
+                assert_eq!(&output[13..18], [1, 1, 44, 1, 44]);
+            }
+        }
+        let source = decode_source(&output, true, 0).expect("Failed to decode output");
+        assert_eq!(source.metadata.dpi, Some(300), "{format:?}");
+
+        // Compressing again keeps the resolution of the input, even when stripping metadata
+        let again = compress_detailed(&output, &forced()).expect("Failed to compress");
+        let source = decode_source(&again.bytes, true, 0).expect("Failed to decode output");
+        assert_eq!(source.metadata.dpi, Some(300), "{format:?}");
+    }
+}
+
+#[test]
+fn posterize_leaves_at_most_the_given_levels_per_channel() {
+    // Every value in every channel, and partial alpha that must stay as is
+    let rgba = RgbaImage::from_fn(256, 4, |x, y| {
+        Rgba([
+            x as u8,
+            255 - x as u8,
+            (x * 7 + y) as u8,
+            (x + y * 60) as u8,
+        ])
+    });
+    let images = [
+        DynamicImage::ImageRgba8(rgba.clone()),
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba.clone()).into_rgb8()),
+        DynamicImage::ImageLuma8(image::GrayImage::from_fn(256, 4, |x, _| {
+            image::Luma([x as u8])
+        })),
+    ];
+    for levels in [2, 3, 4, 7, 16, 255] {
+        for image in &images {
+            let channels = image.color().channel_count() as usize;
+            let posterized = posterize(image.clone(), levels);
+            assert_eq!(posterized.color(), image.color());
+            let bytes = posterized.as_bytes();
+            let color_channels = if image.color().has_alpha() {
+                channels - 1
+            } else {
+                channels
+            };
+            for channel in 0..color_channels {
+                let mut values = bytes
+                    .iter()
+                    .skip(channel)
+                    .step_by(channels)
+                    .copied()
+                    .collect::<Vec<_>>();
+                values.sort();
+                values.dedup();
+                assert!(
+                    values.len() <= levels as usize,
+                    "{:?} channel {channel} has {} levels, not at most {levels}",
+                    image.color(),
+                    values.len()
+                );
+                // The darkest and brightest levels are black and white
+                assert_eq!((values[0], values[values.len() - 1]), (0, 255));
+            }
+            if image.color().has_alpha() {
+                let alpha_kept = posterized
+                    .to_rgba8()
+                    .pixels()
+                    .zip(rgba.pixels())
+                    .all(|(posterized, input)| posterized.0[3] == input.0[3]);
+                assert!(alpha_kept, "Alpha was posterized");
+            }
+        }
+    }
+}
+
+/// Decode every frame of a GIF, composited onto the canvas like a viewer shows them
+fn gif_frames(bytes: &[u8]) -> Vec<RgbaImage> {
+    GifDecoder::new(Cursor::new(bytes))
+        .expect("Failed to decode GIF")
+        .into_frames()
+        .map(|frame| frame.expect("Failed to decode GIF").into_buffer())
+        .collect()
+}
+
+#[test]
+fn gif_transparency_survives_reencoding() {
+    // A transparent hole that moves between frames, over a few opaque colors
+    let frame = |offset: u32| {
+        RgbaImage::from_fn(24, 16, |x, y| {
+            if (offset..offset + 6).contains(&x) && (4..12).contains(&y) {
+                Rgba([0, 0, 0, 0])
+            } else {
+                Rgba([(x / 6 * 60) as u8, (y / 4 * 60) as u8, 120, 255])
+            }
+        })
+    };
+    let frames = [frame(2), frame(12)]
+        .into_iter()
+        .map(|image| GifFrame {
+            image,
+            left: 0,
+            top: 0,
+            delay: 10,
+            dispose: gif::DisposalMethod::Background,
+        })
+        .collect();
+    let input = encode_gif(24, 16, frames);
+
+    for animated in [true, false] {
+        // A single frame GIF is written as a still image
+        let input = if animated {
+            input.clone()
+        } else {
+            encode_gif(
+                24,
+                16,
+                vec![GifFrame {
+                    image: frame(2),
+                    left: 0,
+                    top: 0,
+                    delay: 0,
+                    dispose: gif::DisposalMethod::Keep,
+                }],
+            )
+        };
+        let result = compress_detailed(&input, &forced()).expect("Failed to compress");
+        assert_eq!(
+            image::guess_format(&result.bytes).ok(),
+            Some(ImageFormat::Gif)
+        );
+        let (expected, actual) = (gif_frames(&input), gif_frames(&result.bytes));
+        assert_eq!(expected.len(), actual.len());
+        let alpha = |image: &RgbaImage| image.pixels().map(|pixel| pixel.0[3]).collect::<Vec<_>>();
+        for (expected, actual) in expected.iter().zip(&actual) {
+            assert!(alpha(expected).contains(&0));
+            assert_eq!(alpha(actual), alpha(expected));
+        }
+    }
+}
+
+#[test]
+fn auto_levels_stretch_the_histogram_to_full_range() {
+    // A dull gradient from 60 to 180, with a few outliers that the clipping ignores
+    let mut gray = image::GrayImage::from_fn(121, 100, |x, _| image::Luma([60 + x as u8]));
+    gray.put_pixel(0, 0, image::Luma([0]));
+    gray.put_pixel(1, 0, image::Luma([255]));
+    let rgb = DynamicImage::ImageLuma8(gray.clone()).into_rgb8();
+    for image in [DynamicImage::ImageLuma8(gray), DynamicImage::ImageRgb8(rgb)] {
+        let (black, white) = levels_range(&image).expect("Image has a range to stretch");
+        assert!(
+            (60..=61).contains(&black) && (179..=180).contains(&white),
+            "{black}..{white}"
+        );
+
+        let stretched = stretch_levels(image, black, white);
+        let bytes = stretched.as_bytes();
+        assert_eq!(bytes.iter().min(), Some(&0));
+        assert_eq!(bytes.iter().max(), Some(&255));
+        // The levels in between are spread out, not just clipped at the ends
+        let mut levels = bytes.to_vec();
+        levels.sort();
+        levels.dedup();
+        assert!(levels.len() > 100, "{} levels", levels.len());
+    }
+
+    // A flat image has nothing to stretch
+    let flat = DynamicImage::ImageLuma8(image::GrayImage::from_pixel(8, 8, image::Luma([90])));
+    assert_eq!(levels_range(&flat), None);
+}
+
+#[test]
+fn validate_options_checks_quality_bounds() {
+    for quality in [0, 100] {
+        let options = CompressOptions {
+            quality,
+            jpeg_quality: Some(quality),
+            webp_quality: Some(quality),
+            png_quality: Some(quality),
+            ..CompressOptions::default()
+        };
+        assert!(validate_options(&options).is_ok(), "quality {quality}");
+    }
+
+    let too_high = [
+        CompressOptions {
+            quality: 101,
+            ..CompressOptions::default()
+        },
+        CompressOptions {
+            jpeg_quality: Some(101),
+            ..CompressOptions::default()
+        },
+        CompressOptions {
+            webp_quality: Some(101),
+            ..CompressOptions::default()
+        },
+        CompressOptions {
+            png_quality: Some(101),
+            ..CompressOptions::default()
+        },
+    ];
+    for options in too_high {
+        let error = validate_options(&options).expect_err("Quality 101 was accepted");
+        assert_eq!(error.kind(), ErrorCode::InvalidArgument);
+        assert!(error.message().contains("101"), "{}", error.message());
+    }
+}
+
+#[test]
+fn threshold_alpha_leaves_only_transparent_and_opaque() {
+    let rgba = RgbaImage::from_fn(256, 2, |x, y| Rgba([x as u8, 40, y as u8 * 200, x as u8]));
+    let gray_alpha = DynamicImage::ImageRgba8(rgba.clone()).into_luma_alpha8();
+    for image in [
+        DynamicImage::ImageRgba8(rgba),
+        DynamicImage::ImageLumaA8(gray_alpha),
+    ] {
+        let thresholded = threshold_alpha(image.clone(), 128).to_rgba8();
+        for (output, input) in thresholded.pixels().zip(image.to_rgba8().pixels()) {
+            let expected = if input.0[3] < 128 { 0 } else { 255 };
+            assert_eq!(output.0[3], expected, "alpha {}", input.0[3]);
+            // The colors are left as they are
+            assert_eq!(output.0[..3], input.0[..3]);
+        }
+    }
+
+    // Images without alpha are left as they are
+    let opaque = DynamicImage::ImageRgb8(photo(4, 4));
+    assert_eq!(threshold_alpha(opaque.clone(), 128), opaque);
+}
+
+#[test]
+fn gif_disposal_is_kept_through_reencoding() {
+    let solid = |width, height, color| RgbaImage::from_pixel(width, height, Rgba(color));
+    let background = RgbaImage::from_fn(24, 16, |x, y| {
+        Rgba([(x / 4 * 40) as u8, (y / 4 * 60) as u8, 90, 255])
+    });
+    let frame = |image, left, top, dispose| GifFrame {
+        image,
+        left,
+        top,
+        delay: 10,
+        dispose,
+    };
+    // The red square is restored away, the blue one cleared to transparent before the green one
+    let frames = vec![
+        frame(background, 0, 0, gif::DisposalMethod::Keep),
+        frame(
+            solid(8, 8, [255, 0, 0, 255]),
+            4,
+            4,
+            gif::DisposalMethod::Previous,
+        ),
+        frame(
+            solid(8, 8, [0, 0, 255, 255]),
+            12,
+            6,
+            gif::DisposalMethod::Background,
+        ),
+        frame(
+            solid(4, 4, [0, 255, 0, 255]),
+            0,
+            0,
+            gif::DisposalMethod::Keep,
+        ),
+    ];
+    let input = encode_gif(24, 16, frames);
+
+    let expected = gif_frames(&input);
+    // The canvas under the red square shows again, the area of the blue one is transparent
+    assert_eq!(expected[2].get_pixel(5, 5), expected[0].get_pixel(5, 5));
+    assert_eq!(expected[3].get_pixel(13, 7).0[3], 0);
+
+    for gif_delta_frames in [false, true] {
+        let options = CompressOptions {
+            quality: 100,
+            gif_delta_frames,
+            ..forced()
+        };
+        let result = compress_detailed(&input, &options).expect("Failed to compress");
+        assert_eq!(gif_frames(&result.bytes), expected);
+    }
+}
+
+#[test]
+fn animation_frames_count_towards_max_pixels_together() {
+    // Tiny frames on a 100x100 canvas, each decoded as the whole canvas
+    let gif = |frames: usize| {
+        let frames = (0..frames)
+            .map(|index| GifFrame {
+                image: RgbaImage::from_pixel(1, 1, Rgba([index as u8, 0, 0, 255])),
+                left: index as u16,
+                top: 0,
+                delay: 1,
+                dispose: gif::DisposalMethod::Keep,
+            })
+            .collect();
+        encode_gif(100, 100, frames)
+    };
+    let options = CompressOptions {
+        max_pixels: 100_000,
+        ..forced()
+    };
+
+    let source = decode_source(&gif(10), true, options.max_pixels).expect("Failed to decode");
+    assert_eq!(source.frames.len(), 10);
+
+    let input = gif(200);
+    assert!(input.len() < 8000, "{} bytes", input.len());
+    let error = compress_detailed(&input, &options).expect_err("200 frames were decoded");
+    assert_eq!(error.kind(), ErrorCode::ImageTooLarge);
+    // Without a limit every frame is decoded
+    let source = decode_source(&input, true, 0).expect("Failed to decode");
+    assert_eq!(source.frames.len(), 200);
+}
+
+#[test]
+fn shared_quantization_reports_every_frame() {
+    // Too many colors for an exact palette, so imagequant builds one from all frames
+    let photo = photo(40, 30);
+    let frames = (0..5)
+        .map(|index| {
+            RgbaImage::from_fn(40 - index * 4, 30, |x, y| {
+                let [r, g, b] = photo.get_pixel(x + index * 4, y).0;
+                Rgba([r, g, b, 255])
+            })
+        })
+        .collect::<Vec<_>>();
+    let images = frames.iter().collect::<Vec<_>>();
+    let mut reported = Vec::new();
+    let quantized = quantize_shared(&images, forced().quantization(), |done| {
+        reported.push(done);
+        Ok(())
+    })
+    .expect("Failed to quantize");
+    assert_eq!(reported, [1, 2, 3, 4, 5]);
+    assert!(quantized.palette.len() <= 256);
+    for (indexes, frame) in quantized.indexes.iter().zip(&frames) {
+        assert_eq!(indexes.len(), frame.pixels().len());
+        assert!(
+            indexes
+                .iter()
+                .all(|&index| (index as usize) < quantized.palette.len())
+        );
+    }
+
+    // A failing callback stops the quantization
+    let error = quantize_shared(&images, forced().quantization(), |done| {
+        if done == 2 {
+            Err(Error::new(ErrorCode::CallbackFailed, "Stop"))
+        } else {
+            Ok(())
+        }
+    })
+    .err()
+    .expect("The callback error was dropped");
+    assert_eq!(error.kind(), ErrorCode::CallbackFailed);
+}
+
+#[test]
+fn global_palette_gif_and_apng_keep_their_frames() {
+    let photo = photo(40, 30);
+    let frames = (0..4)
+        .map(|index| GifFrame {
+            image: RgbaImage::from_fn(40, 30, |x, y| {
+                let [r, g, b] = photo.get_pixel((x + index * 3) % 40, y).0;
+                // Few colors per frame, so the input keeps them exactly
+                Rgba([r & 0xc0, g & 0xc0, b & 0xc0, 255])
+            }),
+            left: 0,
+            top: 0,
+            delay: 10,
+            dispose: gif::DisposalMethod::Keep,
+        })
+        .collect();
+    let input = encode_gif(40, 30, frames);
+    let expected = gif_frames(&input);
+
+    let options = CompressOptions {
+        gif_global_palette: true,
+        ..forced()
+    };
+    let result = compress_detailed(&input, &options).expect("Failed to compress");
+    assert_eq!(gif_frames(&result.bytes), expected);
+
+    let apng = gif_to_apng(&input, 75, 1.0).expect("Failed to convert");
+    let decoder = PngDecoder::new(Cursor::new(&apng)).expect("Failed to decode APNG");
+    let frames = decoder
+        .apng()
+        .expect("Failed to decode APNG")
+        .into_frames()
+        .collect_frames()
+        .expect("Failed to decode APNG");
+    let frames = frames
+        .into_iter()
+        .map(Frame::into_buffer)
+        .collect::<Vec<_>>();
+    assert_eq!(frames, expected);
+}
@@ -0,0 +1,166 @@
+use super::metadata::Metadata;
+use image::RgbaImage;
+
+/// VP8X flag: the file has an ICC profile
+const VP8X_ICC: u8 = 0x20;
+/// VP8X flag: some frames have transparency
+const VP8X_ALPHA: u8 = 0x10;
+/// VP8X flag: the file has EXIF metadata
+const VP8X_EXIF: u8 = 0x08;
+/// VP8X flag: the file is an animation
+const VP8X_ANIMATION: u8 = 0x02;
+/// ANMF flag: the frame replaces the canvas instead of being alpha blended onto it
+const ANMF_NO_BLEND: u8 = 0x02;
+
+/// Images smaller than this in both dimensions are left as is by near-lossless preprocessing
+const NEAR_LOSSLESS_MIN_SIZE: u32 = 64;
+
+/// Frame of an animated WebP
+pub(crate) struct AnimationFrame {
+    /// Complete WebP file of the frame, as written by `image_webp::WebPEncoder`
+    pub(crate) webp: Vec<u8>,
+    /// Display duration in milliseconds
+    pub(crate) duration_ms: u32,
+}
+
+/// Assemble still WebP images into an animated WebP
+/// - width: Canvas width, every frame must cover the whole canvas
+/// - height: Canvas height
+/// - frames: Frames to play
+/// - loop_count: Number of times to play the animation, 0 loops forever
+/// - metadata: ICC profile and EXIF to write
+///
+/// The image data chunk of every frame is moved into an ANMF chunk, the rest of its file is dropped.
+pub(crate) fn assemble_animation(
+    width: u32,
+    height: u32,
+    frames: &[AnimationFrame],
+    loop_count: u16,
+    metadata: &Metadata,
+) -> Vec<u8> {
+    let mut chunks = Vec::new();
+
+    let mut flags = VP8X_ALPHA | VP8X_ANIMATION;
+    if metadata.icc_profile.is_some() {
+        flags |= VP8X_ICC;
+    }
+    if metadata.exif.is_some() {
+        flags |= VP8X_EXIF;
+    }
+    let mut vp8x = vec![flags, 0, 0, 0];
+    vp8x.extend_from_slice(&u24(width - 1));
+    vp8x.extend_from_slice(&u24(height - 1));
+    write_chunk(&mut chunks, b"VP8X", &vp8x);
+
+    if let Some(icc_profile) = &metadata.icc_profile {
+        write_chunk(&mut chunks, b"ICCP", icc_profile);
+    }
+
+    // Transparent background, BGRA order
+    let mut anim = vec![0, 0, 0, 0];
+    anim.extend_from_slice(&loop_count.to_le_bytes());
+    write_chunk(&mut chunks, b"ANIM", &anim);
+
+    for frame in frames {
+        // Frame offset is 0, 0
+        let mut anmf = vec![0; 6];
+        anmf.extend_from_slice(&u24(width - 1));
+        anmf.extend_from_slice(&u24(height - 1));
+        anmf.extend_from_slice(&u24(frame.duration_ms.min(0xff_ffff)));
+        anmf.push(ANMF_NO_BLEND);
+        anmf.extend_from_slice(image_chunk(&frame.webp));
+        write_chunk(&mut chunks, b"ANMF", &anmf);
+    }
+
+    if let Some(exif) = &metadata.exif {
+        write_chunk(&mut chunks, b"EXIF", exif);
+    }
+
+    let mut output = Vec::with_capacity(chunks.len() + 12);
+    output.extend_from_slice(b"RIFF");
+    output.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+    output.extend_from_slice(b"WEBP");
+    output.extend_from_slice(&chunks);
+    output
+}
+
+/// Find the VP8L or VP8 chunk of a WebP file, including its header and padding
+fn image_chunk(webp: &[u8]) -> &[u8] {
+    // Skip the 12 byte RIFF header, then walk chunks: type (4), length (4), data, padding
+    let mut position = 12;
+    while let Some(header) = webp.get(position..position + 8) {
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let end = (position + 8 + len + (len & 1)).min(webp.len());
+        if matches!(&header[..4], b"VP8L" | b"VP8 ") {
+            return &webp[position..end];
+        }
+        position = end;
+    }
+    &[]
+}
+
+/// Write a RIFF chunk, padded to an even length
+fn write_chunk(output: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(fourcc);
+    output.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    output.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        output.push(0);
+    }
+}
+
+/// Little endian 24-bit integer
+fn u24(value: u32) -> [u8; 3] {
+    let [a, b, c, _] = value.to_le_bytes();
+    [a, b, c]
+}
+
+/// Near-lossless preprocessing of libwebp, which rounds the low bits of pixels that aren't in a
+/// smooth area so the lossless encoder finds more repetition
+/// - image: Image to process
+/// - level: Near-lossless level (0-100), 100 leaves the image as is and 0 changes it most
+///
+/// Below level 100, pixels differing from a 4-connected neighbor by up to 32 >> (level / 20)
+/// are rounded to that many levels per channel, in passes of decreasing strength. Edge pixels
+/// and small images are kept.
+pub(crate) fn near_lossless(image: &mut RgbaImage, level: u8) {
+    let limit_bits = 5 - level.min(100) as u32 / 20;
+    let (width, height) = image.dimensions();
+    if limit_bits == 0
+        || (width < NEAR_LOSSLESS_MIN_SIZE && height < NEAR_LOSSLESS_MIN_SIZE)
+        || width < 3
+        || height < 3
+    {
+        return;
+    }
+    for bits in (1..=limit_bits).rev() {
+        let source = image.clone();
+        let limit = 1i32 << bits;
+        let is_near = |a: [u8; 4], b: [u8; 4]| {
+            (0..4).all(|channel| (a[channel] as i32 - b[channel] as i32).abs() < limit)
+        };
+        for y in 1..height - 1 {
+            for x in 1..width - 1 {
+                let pixel = source.get_pixel(x, y).0;
+                let smooth = [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)]
+                    .iter()
+                    .all(|&(x, y)| is_near(pixel, source.get_pixel(x, y).0));
+                if !smooth {
+                    image.get_pixel_mut(x, y).0 = pixel.map(|channel| discretize(channel, bits));
+                }
+            }
+        }
+    }
+}
+
+/// Round a channel to the nearest multiple of 2^bits, ties to even, like libwebp
+fn discretize(value: u8, bits: u32) -> u8 {
+    let mask = (1u32 << bits) - 1;
+    let value = value as u32;
+    let biased = value + (mask >> 1) + ((value >> bits) & 1);
+    if biased > 0xff {
+        0xff
+    } else {
+        (biased & !mask) as u8
+    }
+}